@@ -0,0 +1,396 @@
+// Access rules on top of the volume and home sandbox. A rule grants (`allow`)
+// or takes away (`deny`) permissions on a folder or file for one user, one
+// group or everyone, and applies to everything below it. Rules are applied
+// from the volume root down to the path, so a rule deeper in the tree
+// overrides one further up. At the same depth user rules beat group rules,
+// which beat rules for everyone, and a deny beats an allow. Without any rule
+// everything is allowed; admins are never restricted.
+use axum::{
+    extract::{Json, Path as UrlPath},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+    routing::{get, put},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    path::Path,
+    sync::{LazyLock, RwLock},
+};
+
+use crate::{
+    auth::{self, CurrentUser},
+    config, fs_ops, sandbox,
+};
+
+static RULES: LazyLock<RwLock<Vec<Rule>>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    // Create and overwrite entries
+    Write,
+    Delete,
+    // Hand out links to the entry
+    Share,
+}
+
+const ALL: [Permission; 4] = [Permission::Read, Permission::Write, Permission::Delete, Permission::Share];
+
+impl Permission {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Delete => write!(f, "delete"),
+            Permission::Share => write!(f, "share"),
+        }
+    }
+}
+
+fn mask(permissions: &[Permission]) -> u8 {
+    permissions.iter().fold(0, |m, p| m | p.bit())
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Rule {
+    id: String,
+    volume: String,
+    // Relative to the volume root, `/` separated; empty for the whole volume
+    path: String,
+    // At most one of `user` and `group`; neither means everyone
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    allow: Vec<Permission>,
+    #[serde(default)]
+    deny: Vec<Permission>,
+}
+
+impl Rule {
+    // Which of the three tiers the rule is in, if it applies to `user` at all
+    fn tier(&self, user: &CurrentUser) -> Option<usize> {
+        match (&self.user, &self.group) {
+            (Some(name), _) => (*name == user.username).then_some(2),
+            (None, Some(group)) => user.groups.contains(group).then_some(1),
+            (None, None) => Some(0),
+        }
+    }
+}
+
+// Reads the rules file, if there is one yet
+pub fn load() -> io::Result<()> {
+    *RULES.write().unwrap() = fs_ops::read_json_or_default(&config::get().auth.acl_file)?;
+    Ok(())
+}
+
+fn save(rules: &[Rule]) -> io::Result<()> {
+    fs_ops::write_json_atomic(&config::get().auth.acl_file, rules)
+}
+
+// The permissions `user` has at `relative` on `volume`
+fn granted(rules: &[Rule], user: &CurrentUser, volume: &str, relative: &str) -> u8 {
+    let mut granted = mask(&ALL);
+    let mut prefix = String::new();
+    let levels = std::iter::once("").chain(relative.split('/').filter(|p| !p.is_empty()));
+    for level in levels {
+        if !level.is_empty() {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(level);
+        }
+        for tier in 0..3 {
+            let (mut allow, mut deny) = (0, 0);
+            for rule in rules.iter().filter(|r| r.volume == volume && r.path == prefix) {
+                if rule.tier(user) == Some(tier) {
+                    allow |= mask(&rule.allow);
+                    deny |= mask(&rule.deny);
+                }
+            }
+            granted = (granted | allow) & !deny;
+        }
+    }
+    granted
+}
+
+// Whether the current user may do `permission` on `path`, a path returned by
+// `resolve_path`. Links are followed first so a rule can't be side-stepped
+// by going through one.
+pub fn allows(path: &Path, permission: Permission) -> bool {
+    let Some(user) = auth::current_user().filter(|u| !u.is_admin) else {
+        return true;
+    };
+    let Some((volume, relative)) = sandbox::real_volume_relative(path) else {
+        return false;
+    };
+    granted(&RULES.read().unwrap(), &user, &volume.name, &relative) & permission.bit() != 0
+}
+
+pub fn check(path: &Path, permission: Permission) -> Result<(), (StatusCode, String)> {
+    if allows(path, permission) { Ok(()) } else { Err(denied(permission)) }
+}
+
+// Like `check`, but also for everything below `path`, for operations that
+// take a whole folder with them such as deletes, moves and copies. Only the
+// places rules are attached to can differ from `path` itself, so those are
+// all that is looked at.
+pub fn check_tree(path: &Path, permission: Permission) -> Result<(), (StatusCode, String)> {
+    check(path, permission)?;
+    let Some(user) = auth::current_user().filter(|u| !u.is_admin) else {
+        return Ok(());
+    };
+    let Some((volume, relative)) = sandbox::real_volume_relative(path) else {
+        return Err(denied(permission));
+    };
+
+    let rules = RULES.read().unwrap();
+    let below = rules.iter().filter(|r| {
+        r.volume == volume.name
+            && ((relative.is_empty() && !r.path.is_empty())
+                || r.path.strip_prefix(relative.as_str()).is_some_and(|rest| rest.starts_with('/')))
+    });
+    for rule in below {
+        if granted(&rules, &user, &volume.name, &rule.path) & permission.bit() == 0 {
+            return Err(denied(permission));
+        }
+    }
+    Ok(())
+}
+
+fn denied(permission: Permission) -> (StatusCode, String) {
+    (StatusCode::FORBIDDEN, format!("You don't have {} permission here", permission))
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/rules", get(list_rules).post(create_rule))
+        .route("/rules/:id", put(update_rule).delete(delete_rule))
+}
+
+#[derive(Deserialize)]
+struct RuleReq {
+    // The first volume when absent
+    volume: Option<String>,
+    path: String,
+    user: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    allow: Vec<Permission>,
+    #[serde(default)]
+    deny: Vec<Permission>,
+}
+
+impl RuleReq {
+    fn into_rule(self, id: String) -> Result<Rule, (StatusCode, String)> {
+        let volume = sandbox::volume(self.volume.as_deref()).map_err(|e| (e.status(), e.to_string()))?;
+        let mut parts = Vec::new();
+        for part in self.path.split(['/', '\\']) {
+            match part {
+                "" | "." => {}
+                ".." => return Err((StatusCode::BAD_REQUEST, "Invalid path: '..' is not allowed".to_string())),
+                _ => parts.push(part),
+            }
+        }
+        if self.user.is_some() && self.group.is_some() {
+            return Err((StatusCode::BAD_REQUEST, "A rule is for a user or a group, not both".to_string()));
+        }
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "A rule needs something to allow or deny".to_string()));
+        }
+        Ok(Rule {
+            id,
+            volume: volume.name.clone(),
+            path: parts.join("/"),
+            user: self.user.filter(|u| !u.is_empty()),
+            group: self.group.filter(|g| !g.is_empty()),
+            allow: self.allow,
+            deny: self.deny,
+        })
+    }
+}
+
+fn admins_only(current: &CurrentUser) -> Result<(), (StatusCode, String)> {
+    if current.is_admin { Ok(()) } else { Err((StatusCode::FORBIDDEN, "Admins only".to_string())) }
+}
+
+async fn list_rules(current: CurrentUser) -> impl IntoResponse {
+    if let Err(e) = admins_only(&current) {
+        return e.into_response();
+    }
+    let mut rules = RULES.read().unwrap().clone();
+    rules.sort_by(|a, b| (&a.volume, &a.path).cmp(&(&b.volume, &b.path)));
+    AxumJson(rules).into_response()
+}
+
+async fn create_rule(current: CurrentUser, Json(payload): Json<RuleReq>) -> impl IntoResponse {
+    if let Err(e) = admins_only(&current) {
+        return e.into_response();
+    }
+    let rule = match payload.into_rule(uuid::Uuid::new_v4().simple().to_string()) {
+        Ok(rule) => rule,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut rules = RULES.write().unwrap();
+    rules.push(rule.clone());
+    match save(&rules) {
+        Ok(()) => (StatusCode::CREATED, AxumJson(rule)).into_response(),
+        Err(e) => {
+            rules.pop();
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn update_rule(
+    current: CurrentUser,
+    UrlPath(id): UrlPath<String>,
+    Json(payload): Json<RuleReq>,
+) -> impl IntoResponse {
+    if let Err(e) = admins_only(&current) {
+        return e.into_response();
+    }
+    let rule = match payload.into_rule(id.clone()) {
+        Ok(rule) => rule,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut rules = RULES.write().unwrap();
+    let Some(slot) = rules.iter_mut().find(|r| r.id == id) else {
+        return (StatusCode::NOT_FOUND, "No such rule").into_response();
+    };
+    let old = std::mem::replace(slot, rule.clone());
+    match save(&rules) {
+        Ok(()) => AxumJson(rule).into_response(),
+        Err(e) => {
+            *rules.iter_mut().find(|r| r.id == id).unwrap() = old;
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn delete_rule(current: CurrentUser, UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    if let Err(e) = admins_only(&current) {
+        return e.into_response();
+    }
+    let mut rules = RULES.write().unwrap();
+    let Some(position) = rules.iter().position(|r| r.id == id) else {
+        return (StatusCode::NOT_FOUND, "No such rule").into_response();
+    };
+    let removed = rules.remove(position);
+    match save(&rules) {
+        Ok(()) => (StatusCode::OK, "Rule deleted").into_response(),
+        Err(e) => {
+            rules.insert(position, removed);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+// Adds a rule the way `POST /acl/rules` would, for tests of other modules
+#[cfg(test)]
+pub fn add_test_rule(rule: serde_json::Value) {
+    let rule = serde_json::from_value::<RuleReq>(rule).unwrap().into_rule(uuid::Uuid::new_v4().simple().to_string());
+    RULES.write().unwrap().push(rule.unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Permission::*;
+
+    fn rule(path: &str, user: Option<&str>, group: Option<&str>, allow: &[Permission], deny: &[Permission]) -> Rule {
+        Rule {
+            id: String::new(),
+            volume: "data".to_string(),
+            path: path.to_string(),
+            user: user.map(str::to_string),
+            group: group.map(str::to_string),
+            allow: allow.to_vec(),
+            deny: deny.to_vec(),
+        }
+    }
+
+    fn user(name: &str, groups: &[&str]) -> CurrentUser {
+        CurrentUser {
+            username: name.to_string(),
+            is_admin: false,
+            confined: false,
+            quota_bytes: None,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    fn has(rules: &[Rule], user: &CurrentUser, relative: &str, permission: Permission) -> bool {
+        granted(rules, user, "data", relative) & permission.bit() != 0
+    }
+
+    #[test]
+    fn everything_is_allowed_without_rules() {
+        let bob = user("bob", &[]);
+        for permission in ALL {
+            assert!(has(&[], &bob, "a/b", permission));
+        }
+    }
+
+    #[test]
+    fn rules_cover_everything_below_their_path() {
+        let rules = [rule("private", None, None, &[], &[Read, Write])];
+        let bob = user("bob", &[]);
+        assert!(!has(&rules, &bob, "private", Read));
+        assert!(!has(&rules, &bob, "private/deep/file.txt", Write));
+        assert!(has(&rules, &bob, "private/deep/file.txt", Delete));
+        assert!(has(&rules, &bob, "public", Read));
+        assert!(has(&rules, &bob, "privateer", Read));
+    }
+
+    #[test]
+    fn deeper_rules_override_ones_further_up() {
+        let rules = [rule("", None, None, &[], &[Read]), rule("shared", None, None, &[Read], &[])];
+        let bob = user("bob", &[]);
+        assert!(!has(&rules, &bob, "other", Read));
+        assert!(has(&rules, &bob, "shared/file.txt", Read));
+    }
+
+    #[test]
+    fn user_rules_beat_group_rules_which_beat_everyone() {
+        let rules = [
+            rule("docs", None, None, &[], &[Write]),
+            rule("docs", None, Some("editors"), &[Write], &[]),
+            rule("docs", Some("carol"), None, &[], &[Write]),
+        ];
+        assert!(!has(&rules, &user("bob", &[]), "docs", Write));
+        assert!(has(&rules, &user("bob", &["editors"]), "docs", Write));
+        assert!(!has(&rules, &user("carol", &["editors"]), "docs", Write));
+    }
+
+    #[test]
+    fn deny_beats_allow_in_the_same_tier() {
+        let rules = [
+            rule("docs", None, Some("a"), &[Share], &[]),
+            rule("docs", None, Some("b"), &[], &[Share]),
+        ];
+        assert!(!has(&rules, &user("bob", &["a", "b"]), "docs", Share));
+        assert!(has(&rules, &user("bob", &["a"]), "docs", Share));
+    }
+
+    #[test]
+    fn rules_only_apply_to_their_own_volume_and_people() {
+        let mut other = rule("", None, None, &[], &[Read]);
+        other.volume = "backup".to_string();
+        let rules = [other, rule("", Some("carol"), None, &[], &[Read])];
+        assert!(has(&rules, &user("bob", &[]), "file.txt", Read));
+        assert!(!has(&rules, &user("carol", &[]), "file.txt", Read));
+    }
+}
@@ -0,0 +1,597 @@
+// Local user accounts and login sessions. Accounts live in a JSON file with
+// Argon2 password hashes; sessions are kept in memory, so a restart signs
+// everyone out. Clients send the session token either as a bearer token or
+// in the cookie set at login.
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Json, Path as UrlPath, Request, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json as AxumJson, Response},
+    routing::{delete, get, post, put},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config, fs_ops};
+
+const SESSION_COOKIE: &str = "dm_session";
+// Reachable without signing in
+const PUBLIC_PATHS: &[&str] = &["/", "/auth/login"];
+const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct User {
+    username: String,
+    password_hash: String,
+    #[serde(default)]
+    is_admin: bool,
+    // Bytes the user may keep in their home; `homes.default_quota_bytes` when absent
+    #[serde(default)]
+    quota_bytes: Option<u64>,
+    // Names access rules can refer to instead of listing every user
+    #[serde(default)]
+    groups: Vec<String>,
+    // Unix seconds
+    created_at: u64,
+}
+
+impl User {
+    fn current(&self) -> CurrentUser {
+        let homes = config::get().features.homes && !self.is_admin;
+        CurrentUser {
+            username: self.username.clone(),
+            is_admin: self.is_admin,
+            confined: homes,
+            quota_bytes: if homes { self.quota_bytes.or(config::get().homes.default_quota_bytes) } else { None },
+            groups: self.groups.clone(),
+        }
+    }
+}
+
+struct Session {
+    username: String,
+    expires_at: SystemTime,
+}
+
+struct Inner {
+    file: PathBuf,
+    users: RwLock<HashMap<String, User>>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    inner: Arc<Inner>,
+}
+
+// The signed-in user, put on the request by `require_user`
+#[derive(Clone)]
+pub struct CurrentUser {
+    pub username: String,
+    pub is_admin: bool,
+    // Kept inside their home folder on writable volumes
+    pub confined: bool,
+    pub quota_bytes: Option<u64>,
+    pub groups: Vec<String>,
+}
+
+tokio::task_local! {
+    static CURRENT_USER: CurrentUser;
+}
+
+// The user the current request is made by, for code that has no extractor
+// to hand, such as `resolve_path`. `None` with auth turned off.
+pub fn current_user() -> Option<CurrentUser> {
+    CURRENT_USER.try_with(|u| u.clone()).ok()
+}
+
+// Whether the current user may follow or stop a background job or upload
+// that `owner` started. Admins may look at anyone's; without accounts everyone
+// is the same.
+pub fn owns_job(owner: Option<&str>) -> bool {
+    match current_user() {
+        Some(user) => user.is_admin || owner == Some(user.username.as_str()),
+        None => owner.is_none(),
+    }
+}
+
+// Runs `f` as `user`, for requests made on someone's behalf, such as uploads
+// through a drop link
+pub async fn run_as<F: std::future::Future>(user: Option<CurrentUser>, f: F) -> F::Output {
+    match user {
+        Some(user) => CURRENT_USER.scope(user, f).await,
+        None => f.await,
+    }
+}
+
+// Runs `f` as `user`, for blocking work handed off by a request
+pub fn with_user<R>(user: Option<CurrentUser>, f: impl FnOnce() -> R) -> R {
+    match user {
+        Some(user) => CURRENT_USER.sync_scope(user, f),
+        None => f(),
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<CurrentUser>()
+            .cloned()
+            .ok_or_else(|| (StatusCode::UNAUTHORIZED, "Sign in required").into_response())
+    }
+}
+
+impl Auth {
+    // Reads the accounts file. When there are no accounts yet an `admin`
+    // account is created and its password printed once to stderr, so the
+    // server can be signed into on first start. It is kept out of the log,
+    // which may be stored or shipped elsewhere.
+    pub fn load() -> io::Result<Self> {
+        let file = config::get().auth.users_file.clone();
+        let users: Vec<User> = fs_ops::read_json_or_default(&file)?;
+
+        let auth = Auth {
+            inner: Arc::new(Inner {
+                file,
+                users: RwLock::new(users.into_iter().map(|u| (u.username.clone(), u)).collect()),
+                sessions: Default::default(),
+            }),
+        };
+
+        if auth.inner.users.read().unwrap().is_empty() {
+            let password = random_hex(12);
+            auth.insert(User {
+                username: "admin".to_string(),
+                password_hash: hash_password(&password)?,
+                is_admin: true,
+                quota_bytes: None,
+                groups: Vec::new(),
+                created_at: unix_now(),
+            })?;
+            tracing::warn!("created account 'admin', its password is printed on stderr");
+            eprintln!("Password for the new 'admin' account: {}", password);
+            eprintln!("Change it after signing in.");
+        }
+        Ok(auth)
+    }
+
+    fn insert(&self, user: User) -> io::Result<()> {
+        let mut users = self.inner.users.write().unwrap();
+        users.insert(user.username.clone(), user);
+        self.save(&users)
+    }
+
+    fn save(&self, users: &HashMap<String, User>) -> io::Result<()> {
+        let mut list: Vec<&User> = users.values().collect();
+        list.sort_by(|a, b| a.username.cmp(&b.username));
+        fs_ops::write_json_atomic(&self.inner.file, &list)
+    }
+
+    fn user(&self, username: &str) -> Option<User> {
+        self.inner.users.read().unwrap().get(username).cloned()
+    }
+
+    // `username` as they would be signed in, if the account still exists
+    pub fn account(&self, username: &str) -> Option<CurrentUser> {
+        Some(self.user(username)?.current())
+    }
+
+    fn start_session(&self, username: &str) -> (String, SystemTime) {
+        let token = random_hex(32);
+        let expires_at = SystemTime::now() + Duration::from_secs(config::get().auth.session_ttl_secs);
+        let mut sessions = self.inner.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires_at > SystemTime::now());
+        sessions.insert(token.clone(), Session { username: username.to_string(), expires_at });
+        (token, expires_at)
+    }
+
+    fn session_user(&self, token: &str) -> Option<CurrentUser> {
+        let username = {
+            let sessions = self.inner.sessions.lock().unwrap();
+            let session = sessions.get(token).filter(|s| s.expires_at > SystemTime::now())?;
+            session.username.clone()
+        };
+        Some(self.user(&username)?.current())
+    }
+
+    // Ends every session of `username` except `keep`
+    fn end_sessions(&self, username: &str, keep: Option<&str>) {
+        self.inner
+            .sessions
+            .lock()
+            .unwrap()
+            .retain(|token, s| s.username != username || Some(token.as_str()) == keep);
+    }
+}
+
+pub fn router(auth: Auth) -> Router {
+    Router::new()
+        .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route("/me", get(me))
+        .route("/password", post(change_password))
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/:username", delete(delete_user))
+        .route("/users/:username/quota", put(set_quota))
+        .route("/users/:username/groups", put(set_groups))
+        .with_state(auth)
+}
+
+// Rejects requests without a valid session; everything behind it can take
+// a `CurrentUser`
+pub async fn require_user(State(auth): State<Auth>, mut request: Request, next: Next) -> Response {
+    let is_public = PUBLIC_PATHS.contains(&request.uri().path());
+    match session_token(request.headers()).and_then(|t| auth.session_user(&t)) {
+        Some(user) => {
+            request.extensions_mut().insert(user.clone());
+            CURRENT_USER.scope(user, next.run(request)).await
+        }
+        None if is_public => next.run(request).await,
+        None => (StatusCode::UNAUTHORIZED, "Sign in required").into_response(),
+    }
+}
+
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string());
+    bearer.or_else(|| {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == SESSION_COOKIE)
+            .map(|(_, value)| value.to_string())
+    })
+}
+
+#[derive(Deserialize)]
+struct LoginReq {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct LoginResp {
+    token: String,
+    // Unix seconds
+    expires_at: u64,
+}
+
+async fn login(State(auth): State<Auth>, Json(payload): Json<LoginReq>) -> impl IntoResponse {
+    let user = auth.user(&payload.username);
+    // Unknown names still pay for a hash so they can't be told apart by timing
+    let hash = user.as_ref().map(|u| u.password_hash.clone());
+    let valid = tokio::task::spawn_blocking(move || match hash {
+        Some(hash) => verify_password(&hash, &payload.password),
+        None => {
+            let _ = hash_password(&payload.password);
+            false
+        }
+    })
+    .await
+    .unwrap();
+
+    let Some(user) = user.filter(|_| valid) else {
+        return (StatusCode::UNAUTHORIZED, "Wrong username or password").into_response();
+    };
+
+    let (token, expires_at) = auth.start_session(&user.username);
+    let max_age = config::get().auth.session_ttl_secs;
+    let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}", SESSION_COOKIE, token, max_age);
+    let expires_at = expires_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    ([(header::SET_COOKIE, cookie)], AxumJson(LoginResp { token, expires_at })).into_response()
+}
+
+async fn logout(State(auth): State<Auth>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(token) = session_token(&headers) {
+        auth.inner.sessions.lock().unwrap().remove(&token);
+    }
+    let cookie = format!("{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0", SESSION_COOKIE);
+    ([(header::SET_COOKIE, cookie)], "Signed out")
+}
+
+#[derive(Serialize)]
+struct UserInfo {
+    username: String,
+    is_admin: bool,
+    quota_bytes: Option<u64>,
+    groups: Vec<String>,
+    created_at: u64,
+}
+
+impl From<&User> for UserInfo {
+    fn from(user: &User) -> Self {
+        UserInfo {
+            username: user.username.clone(),
+            is_admin: user.is_admin,
+            quota_bytes: user.current().quota_bytes,
+            groups: user.groups.clone(),
+            created_at: user.created_at,
+        }
+    }
+}
+
+async fn me(State(auth): State<Auth>, current: CurrentUser) -> impl IntoResponse {
+    match auth.user(&current.username) {
+        Some(user) => AxumJson(UserInfo::from(&user)).into_response(),
+        None => (StatusCode::UNAUTHORIZED, "Sign in required").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct PasswordReq {
+    current_password: String,
+    new_password: String,
+}
+
+// Other sessions of the user are signed out; the one making the change stays
+async fn change_password(
+    State(auth): State<Auth>,
+    current: CurrentUser,
+    headers: HeaderMap,
+    Json(payload): Json<PasswordReq>,
+) -> impl IntoResponse {
+    if payload.new_password.chars().count() < MIN_PASSWORD_LEN {
+        return (StatusCode::BAD_REQUEST, format!("Passwords need at least {} characters", MIN_PASSWORD_LEN))
+            .into_response();
+    }
+    let Some(mut user) = auth.user(&current.username) else {
+        return (StatusCode::UNAUTHORIZED, "Sign in required").into_response();
+    };
+
+    let hash = user.password_hash.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        if !verify_password(&hash, &payload.current_password) {
+            return Ok(None);
+        }
+        hash_password(&payload.new_password).map(Some)
+    })
+    .await
+    .unwrap();
+
+    match result {
+        Ok(Some(new_hash)) => {
+            user.password_hash = new_hash;
+            if let Err(e) = auth.insert(user) {
+                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+            }
+            auth.end_sessions(&current.username, session_token(&headers).as_deref());
+            (StatusCode::OK, "Password changed").into_response()
+        }
+        Ok(None) => (StatusCode::FORBIDDEN, "Current password is wrong").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn list_users(State(auth): State<Auth>, current: CurrentUser) -> impl IntoResponse {
+    if !current.is_admin {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+    let mut users: Vec<UserInfo> = auth.inner.users.read().unwrap().values().map(UserInfo::from).collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    AxumJson(users).into_response()
+}
+
+#[derive(Deserialize)]
+struct CreateUserReq {
+    username: String,
+    password: String,
+    #[serde(default)]
+    is_admin: bool,
+    quota_bytes: Option<u64>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+async fn create_user(
+    State(auth): State<Auth>,
+    current: CurrentUser,
+    Json(payload): Json<CreateUserReq>,
+) -> impl IntoResponse {
+    if !current.is_admin {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+    if let Err(e) = check_names("Usernames", [&payload.username]).and(check_names("Group names", &payload.groups)) {
+        return e.into_response();
+    }
+    if payload.password.chars().count() < MIN_PASSWORD_LEN {
+        return (StatusCode::BAD_REQUEST, format!("Passwords need at least {} characters", MIN_PASSWORD_LEN))
+            .into_response();
+    }
+    if auth.user(&payload.username).is_some() {
+        return (StatusCode::CONFLICT, "User already exists").into_response();
+    }
+
+    let password = payload.password;
+    let hash = match tokio::task::spawn_blocking(move || hash_password(&password)).await.unwrap() {
+        Ok(h) => h,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let user = User {
+        username: payload.username,
+        password_hash: hash,
+        is_admin: payload.is_admin,
+        quota_bytes: payload.quota_bytes,
+        groups: payload.groups,
+        created_at: unix_now(),
+    };
+    let info = UserInfo::from(&user);
+    match auth.insert(user) {
+        Ok(()) => (StatusCode::CREATED, AxumJson(info)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn delete_user(
+    State(auth): State<Auth>,
+    current: CurrentUser,
+    UrlPath(username): UrlPath<String>,
+) -> impl IntoResponse {
+    if !current.is_admin {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+    if username == current.username {
+        return (StatusCode::BAD_REQUEST, "Cannot delete your own account").into_response();
+    }
+
+    let result = {
+        let mut users = auth.inner.users.write().unwrap();
+        if users.remove(&username).is_none() {
+            return (StatusCode::NOT_FOUND, "No such user").into_response();
+        }
+        auth.save(&users)
+    };
+    auth.end_sessions(&username, None);
+    match result {
+        Ok(()) => (StatusCode::OK, "User deleted").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct QuotaReq {
+    // `null` falls back to `homes.default_quota_bytes`
+    quota_bytes: Option<u64>,
+}
+
+async fn set_quota(
+    State(auth): State<Auth>,
+    current: CurrentUser,
+    UrlPath(username): UrlPath<String>,
+    Json(payload): Json<QuotaReq>,
+) -> impl IntoResponse {
+    if !current.is_admin {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+    let Some(mut user) = auth.user(&username) else {
+        return (StatusCode::NOT_FOUND, "No such user").into_response();
+    };
+    user.quota_bytes = payload.quota_bytes;
+    let info = UserInfo::from(&user);
+    match auth.insert(user) {
+        Ok(()) => AxumJson(info).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct GroupsReq {
+    groups: Vec<String>,
+}
+
+async fn set_groups(
+    State(auth): State<Auth>,
+    current: CurrentUser,
+    UrlPath(username): UrlPath<String>,
+    Json(mut payload): Json<GroupsReq>,
+) -> impl IntoResponse {
+    if !current.is_admin {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+    if let Err(e) = check_names("Group names", &payload.groups) {
+        return e.into_response();
+    }
+    let Some(mut user) = auth.user(&username) else {
+        return (StatusCode::NOT_FOUND, "No such user").into_response();
+    };
+    payload.groups.sort();
+    payload.groups.dedup();
+    user.groups = payload.groups;
+    let info = UserInfo::from(&user);
+    match auth.insert(user) {
+        Ok(()) => AxumJson(info).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// For user and group names. User names become home folder names, so `.`,
+// `..` and hidden names are out.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// `what` names the kind of name in the message, e.g. "Group names"
+fn check_names<'a>(what: &str, names: impl IntoIterator<Item = &'a String>) -> Result<(), (StatusCode, String)> {
+    if names.into_iter().all(|name| valid_name(name)) {
+        return Ok(());
+    }
+    let message = format!("{} may only use letters, digits, '-', '_' and '.', and can't start with '.'", what);
+    Err((StatusCode::BAD_REQUEST, message))
+}
+
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let mut salt = [0u8; 16];
+    getrandom::fill(&mut salt).map_err(io::Error::other)?;
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default()).map_err(io::Error::other)
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
+
+pub fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    getrandom::fill(&mut buf).expect("no randomness available");
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// A plain account for tests, neither admin nor kept to a home
+#[cfg(test)]
+pub fn test_user(username: &str) -> CurrentUser {
+    CurrentUser {
+        username: username.to_string(),
+        is_admin: false,
+        confined: false,
+        quota_bytes: None,
+        groups: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_follow_one_set_of_rules() {
+        for name in ["bob", "a.b", "team-1_x"] {
+            assert!(valid_name(name), "{}", name);
+        }
+        for name in ["", ".", "..", ".hidden", "a/b", "a b", &"x".repeat(65)] {
+            assert!(!valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn users_and_groups_are_refused_with_the_same_message() {
+        let groups = vec!["ok".to_string(), ".bad".to_string()];
+        let (status, groups_message) = check_names("Group names", &groups).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, user_message) = check_names("Usernames", [&"..".to_string()]).unwrap_err();
+        assert_eq!(groups_message.strip_prefix("Group names"), user_message.strip_prefix("Usernames"));
+        assert!(check_names("Group names", &Vec::new()).is_ok());
+    }
+}
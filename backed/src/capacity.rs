@@ -0,0 +1,79 @@
+// How full the filesystem behind each volume is
+use axum::{
+    http::{HeaderMap, HeaderName},
+    response::{IntoResponse, Json as AxumJson},
+};
+use serde::Serialize;
+use std::{io, path::Path};
+
+use crate::sandbox;
+
+// Sent with listings that ask for `capacity=true`
+const CAPACITY_TOTAL: HeaderName = HeaderName::from_static("x-capacity-total");
+const CAPACITY_USED: HeaderName = HeaderName::from_static("x-capacity-used");
+const CAPACITY_AVAILABLE: HeaderName = HeaderName::from_static("x-capacity-available");
+
+pub const EXPOSED_HEADERS: [HeaderName; 3] = [CAPACITY_TOTAL, CAPACITY_USED, CAPACITY_AVAILABLE];
+
+#[derive(Serialize)]
+pub struct Capacity {
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    // What can still be written without root privileges, which is usually
+    // less than total minus used
+    pub available_bytes: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+}
+
+impl Capacity {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(CAPACITY_TOTAL, self.total_bytes.into());
+        headers.insert(CAPACITY_USED, self.used_bytes.into());
+        headers.insert(CAPACITY_AVAILABLE, self.available_bytes.into());
+    }
+}
+
+#[cfg(unix)]
+pub fn of(path: &Path) -> io::Result<Capacity> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path =
+        CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Block counts are in units of the fragment size
+    let unit = stat.f_frsize as u64;
+    let (blocks, free, available) = (stat.f_blocks as u64, stat.f_bfree as u64, stat.f_bavail as u64);
+    Ok(Capacity {
+        total_bytes: blocks * unit,
+        used_bytes: blocks.saturating_sub(free) * unit,
+        available_bytes: available * unit,
+        total_inodes: stat.f_files as u64,
+        free_inodes: stat.f_ffree as u64,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn of(_path: &Path) -> io::Result<Capacity> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "capacity is only reported on unix"))
+}
+
+#[derive(Serialize)]
+struct VolumeCapacity {
+    name: String,
+    // Absent when the filesystem couldn't be asked
+    #[serde(flatten)]
+    capacity: Option<Capacity>,
+}
+
+pub async fn report() -> impl IntoResponse {
+    let volumes: Vec<VolumeCapacity> = sandbox::volumes()
+        .into_iter()
+        .map(|v| VolumeCapacity { name: v.name.clone(), capacity: of(&v.path).ok() })
+        .collect();
+    AxumJson(volumes)
+}
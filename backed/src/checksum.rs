@@ -0,0 +1,161 @@
+// Checksums of a file, computed on request so clients can verify what they
+// downloaded. Every algorithm asked for is fed from a single read of the file.
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    acl::{self, Permission},
+    resolve_path, sandbox,
+};
+
+const READ_BUFFER: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Algorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(Algorithm::Sha256),
+            "sha1" => Some(Algorithm::Sha1),
+            "md5" => Some(Algorithm::Md5),
+            "blake3" => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Md5 => "md5",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Algorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha1(sha1::Sha1),
+    Md5(md5::Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    // Lowercase hex, as `sha256sum` and friends print it
+    fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha1(h) => format!("{:x}", h.finalize()),
+            Hasher::Md5(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChecksumQuery {
+    path: String,
+    volume: Option<String>,
+    // Comma separated, e.g. `sha256,md5`; SHA-256 alone by default
+    algorithms: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Checksums {
+    path: String,
+    size: u64,
+    // Algorithm name to hex digest
+    checksums: BTreeMap<&'static str, String>,
+}
+
+pub async fn checksum(Query(params): Query<ChecksumQuery>) -> impl IntoResponse {
+    let path = match resolve_path(params.volume.as_deref(), Some(params.path)) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&path, Permission::Read) {
+        return e.into_response();
+    }
+    if !path.is_file() {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    }
+
+    let mut algorithms = Vec::new();
+    for name in params.algorithms.as_deref().unwrap_or("sha256").split(',').filter(|n| !n.trim().is_empty()) {
+        match Algorithm::parse(name) {
+            Some(algorithm) => algorithms.push(algorithm),
+            None => {
+                let message = format!("Unknown algorithm '{}'; use sha256, sha1, md5 or blake3", name.trim());
+                return (StatusCode::BAD_REQUEST, message).into_response();
+            }
+        }
+    }
+    algorithms.sort();
+    algorithms.dedup();
+    if algorithms.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No algorithm given").into_response();
+    }
+
+    let hashed = path.clone();
+    match tokio::task::spawn_blocking(move || digest(&hashed, &algorithms)).await.unwrap() {
+        Ok((size, checksums)) => {
+            AxumJson(Checksums { path: sandbox::relative_path(&path), size, checksums }).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+fn digest(path: &Path, algorithms: &[Algorithm]) -> io::Result<(u64, BTreeMap<&'static str, String>)> {
+    let mut file = File::open(path)?;
+    let mut hashers: Vec<(Algorithm, Hasher)> = algorithms.iter().map(|a| (*a, a.hasher())).collect();
+    let mut buf = vec![0; READ_BUFFER];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        size += read as u64;
+        for (_, hasher) in &mut hashers {
+            hasher.update(&buf[..read]);
+        }
+    }
+    Ok((size, hashers.into_iter().map(|(a, h)| (a.name(), h.finish())).collect()))
+}
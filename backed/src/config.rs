@@ -0,0 +1,508 @@
+// Server settings. Each layer overrides the one before it: built-in
+// defaults, then the TOML file, then `DISK_MANAGER_*` environment variables,
+// then command line flags.
+use clap::{builder::BoolishValueParser, Parser};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tracing_subscriber::filter::LevelFilter;
+
+// Read when present and no other file is named
+const DEFAULT_CONFIG_FILE: &str = "disk_manager.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Served as a single volume named `storage` when `volumes` is empty
+    pub storage_root: PathBuf,
+    // Named roots; requests without a `volume` parameter use the first
+    pub volumes: Vec<Volume>,
+    pub listen: Vec<SocketAddr>,
+    // Bytes, for every request body except uploads
+    pub max_body_bytes: u64,
+    // Bytes per `/upload` request; unlimited when absent
+    pub max_upload_bytes: Option<u64>,
+    // Unfinished resumable uploads; best kept outside the volumes so they never show up in listings
+    pub uploads_dir: PathBuf,
+    // `*` allows any origin
+    pub cors_origins: Vec<String>,
+    // off, error, warn, info, debug or trace
+    pub log_level: String,
+    // Follow symlinks that stay inside their volume, or refuse them all
+    pub follow_symlinks: bool,
+    pub auth: AuthConfig,
+    pub homes: HomesConfig,
+    pub links: LinksConfig,
+    pub versions: VersionsConfig,
+    pub trash: TrashConfig,
+    pub features: Features,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomesConfig {
+    // Folder at the root of each writable volume that holds one folder per user
+    pub folder: String,
+    // Bytes each user may store across their homes; unlimited when absent
+    pub default_quota_bytes: Option<u64>,
+}
+
+impl Default for HomesConfig {
+    fn default() -> Self {
+        HomesConfig { folder: "home".to_string(), default_quota_bytes: None }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinksConfig {
+    // Share links handed out under `/shares`
+    pub shares_file: PathBuf,
+    // Upload-only links handed out under `/drops`
+    pub drops_file: PathBuf,
+}
+
+impl Default for LinksConfig {
+    fn default() -> Self {
+        LinksConfig { shares_file: PathBuf::from("shares.json"), drops_file: PathBuf::from("drops.json") }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VersionsConfig {
+    // Versions kept per file, newest first; unlimited when absent
+    pub keep_last: Option<usize>,
+    // Days a version is kept for; forever when absent
+    pub keep_days: Option<u64>,
+}
+
+impl Default for VersionsConfig {
+    fn default() -> Self {
+        VersionsConfig { keep_last: Some(10), keep_days: Some(30) }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    // Days a deleted entry stays in the trash; forever when absent
+    pub keep_days: Option<u64>,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig { keep_days: Some(30) }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Accounts with their password hashes
+    pub users_file: PathBuf,
+    // How long a login lasts
+    pub session_ttl_secs: u64,
+    // Access rules managed under `/acl`
+    pub acl_file: PathBuf,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            users_file: PathBuf::from("users.json"),
+            session_ttl_secs: 7 * 24 * 60 * 60,
+            acl_file: PathBuf::from("acl.json"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Volume {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl std::str::FromStr for Volume {
+    type Err = String;
+
+    // `name=path` for a writable volume, `name:ro=path` for a read-only one
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s.split_once('=').ok_or("expected name=path or name:ro=path")?;
+        let (name, read_only) = match name.strip_suffix(":ro") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        Ok(Volume { name: name.to_string(), path: PathBuf::from(path), read_only })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    // Require signing in; without it anyone who can reach the server has full access
+    pub auth: bool,
+    // Keep users other than admins inside their own home folder
+    pub homes: bool,
+    pub search: bool,
+    pub content_index: bool,
+    // Without it deletes are permanent
+    pub trash: bool,
+    // The tus endpoints under `/uploads`
+    pub resumable_uploads: bool,
+    // Public links to a file or folder under `/s`
+    pub shares: bool,
+    // Public upload-only links to a folder under `/d`
+    pub drops: bool,
+    // Keep what an upload replaces, under `/versions`
+    pub versions: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            storage_root: PathBuf::from("storage"),
+            volumes: Vec::new(),
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            max_body_bytes: 1024 * 1024 * 1024, // 1GB
+            max_upload_bytes: None,
+            uploads_dir: PathBuf::from(".uploads"),
+            cors_origins: vec!["*".to_string()],
+            log_level: "info".to_string(),
+            follow_symlinks: true,
+            auth: AuthConfig::default(),
+            homes: HomesConfig::default(),
+            links: LinksConfig::default(),
+            versions: VersionsConfig::default(),
+            trash: TrashConfig::default(),
+            features: Features::default(),
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            auth: true,
+            homes: true,
+            search: true,
+            content_index: true,
+            trash: true,
+            resumable_uploads: true,
+            shares: true,
+            drops: true,
+            versions: true,
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Disk Manager backend")]
+struct Args {
+    /// TOML file to read settings from
+    #[arg(long, env = "DISK_MANAGER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Folder that holds the served files
+    #[arg(long, env = "DISK_MANAGER_STORAGE_ROOT")]
+    storage_root: Option<PathBuf>,
+    /// Named root as name=path, or name:ro=path for read-only; repeat or comma-separate
+    #[arg(long = "volume", env = "DISK_MANAGER_VOLUMES", value_delimiter = ',')]
+    volumes: Option<Vec<Volume>>,
+    /// Address to listen on; repeat or comma-separate for several
+    #[arg(long, env = "DISK_MANAGER_LISTEN", value_delimiter = ',')]
+    listen: Option<Vec<SocketAddr>>,
+    /// Largest request body in bytes, uploads aside
+    #[arg(long, env = "DISK_MANAGER_MAX_BODY_BYTES")]
+    max_body_bytes: Option<u64>,
+    /// Largest upload request in bytes
+    #[arg(long, env = "DISK_MANAGER_MAX_UPLOAD_BYTES")]
+    max_upload_bytes: Option<u64>,
+    /// Folder that holds unfinished resumable uploads
+    #[arg(long, env = "DISK_MANAGER_UPLOADS_DIR")]
+    uploads_dir: Option<PathBuf>,
+    /// Allowed CORS origins, comma separated, or `*`
+    #[arg(long, env = "DISK_MANAGER_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Option<Vec<String>>,
+    /// off, error, warn, info, debug or trace
+    #[arg(long, env = "DISK_MANAGER_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Follow symlinks that stay inside their volume
+    #[arg(long, env = "DISK_MANAGER_FOLLOW_SYMLINKS", value_parser = BoolishValueParser::new())]
+    follow_symlinks: Option<bool>,
+    /// File holding user accounts
+    #[arg(long, env = "DISK_MANAGER_USERS_FILE")]
+    users_file: Option<PathBuf>,
+    /// Seconds a login stays valid
+    #[arg(long, env = "DISK_MANAGER_SESSION_TTL_SECS")]
+    session_ttl_secs: Option<u64>,
+    /// File holding access rules
+    #[arg(long, env = "DISK_MANAGER_ACL_FILE")]
+    acl_file: Option<PathBuf>,
+    /// Require signing in
+    #[arg(long, env = "DISK_MANAGER_AUTH", value_parser = BoolishValueParser::new())]
+    auth: Option<bool>,
+    /// Keep users other than admins inside their home folder
+    #[arg(long, env = "DISK_MANAGER_HOMES", value_parser = BoolishValueParser::new())]
+    homes: Option<bool>,
+    /// Default per-user quota in bytes
+    #[arg(long, env = "DISK_MANAGER_DEFAULT_QUOTA_BYTES")]
+    default_quota_bytes: Option<u64>,
+    /// Enable `/search`
+    #[arg(long, env = "DISK_MANAGER_SEARCH", value_parser = BoolishValueParser::new())]
+    search: Option<bool>,
+    /// Enable the full-text index and `/content_search`
+    #[arg(long, env = "DISK_MANAGER_CONTENT_INDEX", value_parser = BoolishValueParser::new())]
+    content_index: Option<bool>,
+    /// Move deleted entries into the trash
+    #[arg(long, env = "DISK_MANAGER_TRASH", value_parser = BoolishValueParser::new())]
+    trash: Option<bool>,
+    /// Enable tus uploads under `/uploads`
+    #[arg(long, env = "DISK_MANAGER_RESUMABLE_UPLOADS", value_parser = BoolishValueParser::new())]
+    resumable_uploads: Option<bool>,
+    /// Enable share links
+    #[arg(long, env = "DISK_MANAGER_SHARES", value_parser = BoolishValueParser::new())]
+    shares: Option<bool>,
+    /// File holding share links
+    #[arg(long, env = "DISK_MANAGER_SHARES_FILE")]
+    shares_file: Option<PathBuf>,
+    /// Enable drop links
+    #[arg(long, env = "DISK_MANAGER_DROPS", value_parser = BoolishValueParser::new())]
+    drops: Option<bool>,
+    /// File holding drop links
+    #[arg(long, env = "DISK_MANAGER_DROPS_FILE")]
+    drops_file: Option<PathBuf>,
+    /// Keep the previous content of files replaced by uploads
+    #[arg(long, env = "DISK_MANAGER_VERSIONS", value_parser = BoolishValueParser::new())]
+    versions: Option<bool>,
+    /// Versions kept per file
+    #[arg(long, env = "DISK_MANAGER_VERSIONS_KEEP_LAST")]
+    versions_keep_last: Option<usize>,
+    /// Days a version is kept for
+    #[arg(long, env = "DISK_MANAGER_VERSIONS_KEEP_DAYS")]
+    versions_keep_days: Option<u64>,
+    /// Days deleted entries stay in the trash
+    #[arg(long, env = "DISK_MANAGER_TRASH_KEEP_DAYS")]
+    trash_keep_days: Option<u64>,
+    /// Print the effective settings as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+}
+
+// Builds the settings from every layer. Problems are collected rather than
+// stopping at the first, so one failed start shows everything to fix.
+pub fn load() -> Result<(Config, bool), Vec<String>> {
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => read_file(path)?,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_file(Path::new(DEFAULT_CONFIG_FILE))?,
+        None => Config::default(),
+    };
+
+    // clap already prefers a flag over its environment variable
+    if let Some(v) = args.storage_root {
+        config.storage_root = v;
+    }
+    if let Some(v) = args.volumes {
+        config.volumes = v;
+    }
+    if let Some(v) = args.listen {
+        config.listen = v;
+    }
+    if let Some(v) = args.max_body_bytes {
+        config.max_body_bytes = v;
+    }
+    if let Some(v) = args.max_upload_bytes {
+        config.max_upload_bytes = Some(v);
+    }
+    if let Some(v) = args.uploads_dir {
+        config.uploads_dir = v;
+    }
+    if let Some(v) = args.cors_origins {
+        config.cors_origins = v;
+    }
+    if let Some(v) = args.log_level {
+        config.log_level = v;
+    }
+    if let Some(v) = args.follow_symlinks {
+        config.follow_symlinks = v;
+    }
+    if let Some(v) = args.users_file {
+        config.auth.users_file = v;
+    }
+    if let Some(v) = args.session_ttl_secs {
+        config.auth.session_ttl_secs = v;
+    }
+    if let Some(v) = args.acl_file {
+        config.auth.acl_file = v;
+    }
+    if let Some(v) = args.auth {
+        config.features.auth = v;
+    }
+    if let Some(v) = args.homes {
+        config.features.homes = v;
+    }
+    if let Some(v) = args.default_quota_bytes {
+        config.homes.default_quota_bytes = Some(v);
+    }
+    if let Some(v) = args.search {
+        config.features.search = v;
+    }
+    if let Some(v) = args.content_index {
+        config.features.content_index = v;
+    }
+    if let Some(v) = args.trash {
+        config.features.trash = v;
+    }
+    if let Some(v) = args.resumable_uploads {
+        config.features.resumable_uploads = v;
+    }
+    if let Some(v) = args.shares {
+        config.features.shares = v;
+    }
+    if let Some(v) = args.shares_file {
+        config.links.shares_file = v;
+    }
+    if let Some(v) = args.drops {
+        config.features.drops = v;
+    }
+    if let Some(v) = args.drops_file {
+        config.links.drops_file = v;
+    }
+    if let Some(v) = args.versions {
+        config.features.versions = v;
+    }
+    if let Some(v) = args.versions_keep_last {
+        config.versions.keep_last = Some(v);
+    }
+    if let Some(v) = args.versions_keep_days {
+        config.versions.keep_days = Some(v);
+    }
+    if let Some(v) = args.trash_keep_days {
+        config.trash.keep_days = Some(v);
+    }
+
+    if config.volumes.is_empty() {
+        config.volumes.push(Volume { name: "storage".to_string(), path: config.storage_root.clone(), read_only: false });
+    }
+
+    config.validate()?;
+    Ok((config, args.print_config))
+}
+
+pub fn valid_volume_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn read_file(path: &Path) -> Result<Config, Vec<String>> {
+    let text = std::fs::read_to_string(path).map_err(|e| vec![format!("{}: {}", path.display(), e)])?;
+    toml::from_str(&text).map_err(|e| vec![format!("{}: {}", path.display(), e)])
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        for (i, volume) in self.volumes.iter().enumerate() {
+            if !valid_volume_name(&volume.name) {
+                errors.push(format!("volume '{}': names may only use letters, digits, '-' and '_'", volume.name));
+            }
+            if self.volumes[..i].iter().any(|v| v.name == volume.name) {
+                errors.push(format!("volume '{}' is defined more than once", volume.name));
+            }
+            if volume.path.as_os_str().is_empty() {
+                errors.push(format!("volume '{}': path must not be empty", volume.name));
+            } else if volume.path.exists() && !volume.path.is_dir() {
+                errors.push(format!("volume '{}': {} is not a folder", volume.name, volume.path.display()));
+            } else if volume.read_only && !volume.path.exists() {
+                // Writable roots are created on startup, read-only ones have to exist
+                errors.push(format!("volume '{}': {} does not exist", volume.name, volume.path.display()));
+            }
+        }
+        if self.listen.is_empty() {
+            errors.push("listen needs at least one address".to_string());
+        }
+        if self.max_body_bytes == 0 {
+            errors.push("max_body_bytes must be greater than 0".to_string());
+        }
+        if self.max_upload_bytes == Some(0) {
+            errors.push("max_upload_bytes must be greater than 0".to_string());
+        }
+        if self.uploads_dir.as_os_str().is_empty() {
+            errors.push("uploads_dir must not be empty".to_string());
+        }
+        for origin in &self.cors_origins {
+            let valid = origin == "*"
+                || ((origin.starts_with("http://") || origin.starts_with("https://"))
+                    && axum::http::HeaderValue::from_str(origin).is_ok());
+            if !valid {
+                errors.push(format!("cors_origins: '{}' is not `*` or an http(s) origin", origin));
+            }
+        }
+        let plain_folder = !self.homes.folder.is_empty()
+            && !self.homes.folder.starts_with('.')
+            && !self.homes.folder.contains(['/', '\\']);
+        if !plain_folder {
+            errors.push(format!("homes.folder: '{}' must be a plain folder name", self.homes.folder));
+        }
+        if self.versions.keep_last == Some(0) || self.versions.keep_days == Some(0) {
+            errors.push("versions: keep_last and keep_days must be greater than 0".to_string());
+        }
+        if self.trash.keep_days == Some(0) {
+            errors.push("trash.keep_days must be greater than 0".to_string());
+        }
+        if self.auth.session_ttl_secs == 0 {
+            errors.push("auth.session_ttl_secs must be greater than 0".to_string());
+        }
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            errors.push(format!("log_level: unknown level '{}'", self.log_level));
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    pub fn log_filter(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::INFO)
+    }
+}
+
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+// The settings the server started with. Only valid after `init`.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config not loaded")
+}
+
+// Tests share one set of settings like a running server does: a volume named
+// `data` and the record files, all in a fresh temporary folder
+#[cfg(test)]
+pub fn for_tests() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        let temp = std::env::temp_dir().canonicalize().unwrap();
+        let root = temp.join(format!("disk_manager_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let volume = root.join("volume");
+        std::fs::create_dir_all(&volume).unwrap();
+        Config {
+            volumes: vec![Volume { name: "data".to_string(), path: volume, read_only: false }],
+            uploads_dir: root.join("uploads"),
+            auth: AuthConfig {
+                users_file: root.join("users.json"),
+                acl_file: root.join("acl.json"),
+                ..Default::default()
+            },
+            links: LinksConfig { shares_file: root.join("shares.json"), drops_file: root.join("drops.json") },
+            ..Default::default()
+        }
+    })
+}
@@ -0,0 +1,351 @@
+// Full-text index over the text-like files in storage. It is built in the
+// background at startup and then kept current by the handlers that change
+// files, which call `refresh` / `remove` for the paths they touched.
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    acl::{self, Permission},
+    auth, config, resolve_path, sandbox,
+};
+
+// Larger files are left out of the index
+const MAX_INDEXED_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_TOKEN_LEN: usize = 64;
+const DEFAULT_LIMIT: usize = 50;
+const SNIPPETS_PER_FILE: usize = 5;
+const SNIPPET_CHARS: usize = 240;
+
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "log", "csv", "tsv", "json", "yaml", "yml", "toml", "ini", "cfg", "conf", "xml",
+    "html", "htm", "css", "js", "ts", "jsx", "tsx", "rs", "dart", "py", "rb", "go", "java", "kt", "c", "h",
+    "cpp", "hpp", "cs", "swift", "sh", "bat", "ps1", "sql", "gradle", "properties", "srt", "vtt",
+];
+
+struct IndexedFile {
+    modified: Option<SystemTime>,
+    size: u64,
+    tokens: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    files: HashMap<PathBuf, IndexedFile>,
+    // Ordered so a query word can match every token it is a prefix of
+    postings: BTreeMap<String, HashSet<PathBuf>>,
+}
+
+impl Inner {
+    fn insert(&mut self, path: PathBuf, file: IndexedFile) {
+        self.remove_file(&path);
+        for token in &file.tokens {
+            self.postings.entry(token.clone()).or_default().insert(path.clone());
+        }
+        self.files.insert(path, file);
+    }
+
+    fn remove_file(&mut self, path: &Path) {
+        let Some(old) = self.files.remove(path) else {
+            return;
+        };
+        for token in old.tokens {
+            if let Some(paths) = self.postings.get_mut(&token) {
+                paths.remove(path);
+                if paths.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    fn files_with_prefix(&self, prefix: &str) -> HashSet<PathBuf> {
+        self.postings
+            .range(prefix.to_string()..)
+            .take_while(|(token, _)| token.starts_with(prefix))
+            .flat_map(|(_, paths)| paths.iter().cloned())
+            .collect()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ContentIndex {
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl ContentIndex {
+    // Indexes everything in every volume without holding up startup
+    pub fn build_in_background(&self) {
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || {
+            for volume in sandbox::volumes() {
+                index.index_tree(&volume.path);
+            }
+            let count = index.inner.read().unwrap().files.len();
+            tracing::info!("content index ready, {} files", count);
+        });
+    }
+
+    // Re-reads a file, or every file under a folder, after it was written
+    pub fn refresh(&self, path: PathBuf) {
+        if !config::get().features.content_index {
+            return;
+        }
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || index.index_tree(&path));
+    }
+
+    // Drops a file, or everything under a folder, from the index
+    pub fn remove(&self, path: &Path) {
+        let mut inner = self.inner.write().unwrap();
+        let gone: Vec<PathBuf> = inner.files.keys().filter(|p| p.starts_with(path)).cloned().collect();
+        for p in gone {
+            inner.remove_file(&p);
+        }
+    }
+
+    fn index_tree(&self, root: &Path) {
+        let entries = WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file());
+        for entry in entries {
+            self.index_file(entry.path());
+        }
+    }
+
+    fn index_file(&self, path: &Path) {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            self.inner.write().unwrap().remove_file(path);
+            return;
+        };
+        let modified = metadata.modified().ok();
+
+        let unchanged = self
+            .inner
+            .read()
+            .unwrap()
+            .files
+            .get(path)
+            .is_some_and(|f| f.modified == modified && f.size == metadata.len());
+        if unchanged {
+            return;
+        }
+
+        match read_text(path, metadata.len()) {
+            Some(text) => {
+                let tokens = tokenize(&text).collect();
+                let file = IndexedFile { modified, size: metadata.len(), tokens };
+                self.inner.write().unwrap().insert(path.to_path_buf(), file);
+            }
+            None => self.inner.write().unwrap().remove_file(path),
+        }
+    }
+
+    // Files under `scope` that contain a token starting with every query word
+    fn candidates(&self, words: &[String], scope: &Path) -> Vec<PathBuf> {
+        let inner = self.inner.read().unwrap();
+        let mut result: Option<HashSet<PathBuf>> = None;
+
+        for token in words.iter().flat_map(|w| tokenize(w)) {
+            let files = inner.files_with_prefix(&token);
+            result = Some(match result {
+                Some(acc) => acc.intersection(&files).cloned().collect(),
+                None => files,
+            });
+        }
+
+        let mut paths: Vec<PathBuf> = result.unwrap_or_default().into_iter().filter(|p| p.starts_with(scope)).collect();
+        paths.sort();
+        paths
+    }
+}
+
+fn read_text(path: &Path, size: u64) -> Option<String> {
+    if size > MAX_INDEXED_SIZE {
+        return None;
+    }
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    let known_text = extension.as_deref().is_some_and(|e| TEXT_EXTENSIONS.contains(&e));
+    if extension.is_some() && !known_text {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(size as usize);
+    std::fs::File::open(path).ok()?.read_to_end(&mut data).ok()?;
+    // Extensions can mislead (`.ts` is TypeScript or an MPEG stream), so
+    // anything with NUL bytes near the start is treated as binary
+    if data.iter().take(8192).any(|&b| b == 0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+// Lowercased runs of letters and digits. Scripts written without spaces,
+// such as Chinese or Japanese, are indexed one character at a time.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        if is_cjk(c) {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            tokens.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            current.extend(c.to_lowercase());
+        } else if !current.is_empty() {
+            tokens.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens.into_iter().filter(|t| t.len() <= MAX_TOKEN_LEN)
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF      // Hiragana, Katakana
+        | 0x3400..=0x4DBF    // CJK Extension A
+        | 0x4E00..=0x9FFF    // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF    // Hangul syllables
+        | 0xF900..=0xFAFF)   // CJK Compatibility Ideographs
+}
+
+#[derive(Deserialize)]
+pub struct ContentSearchQuery {
+    q: String,
+    // Only search under this folder
+    path: Option<String>,
+    volume: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ContentMatch {
+    // Relative to the volume root
+    path: String,
+    lines: Vec<LineMatch>,
+}
+
+#[derive(Serialize)]
+struct LineMatch {
+    // 1-based
+    line: usize,
+    text: String,
+    // `[start, end)` character offsets into `text` of each query word
+    highlights: Vec<[usize; 2]>,
+}
+
+pub async fn content_search(
+    State(index): State<ContentIndex>,
+    Query(query): Query<ContentSearchQuery>,
+) -> impl IntoResponse {
+    let scope = match resolve_path(query.volume.as_deref(), query.path) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&scope, Permission::Read) {
+        return e.into_response();
+    }
+    let words: Vec<String> = query.q.split_whitespace().map(str::to_lowercase).collect();
+    if words.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Empty query").into_response();
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+
+    let user = auth::current_user();
+    let results = tokio::task::spawn_blocking(move || {
+        auth::with_user(user, || {
+            index
+                .candidates(&words, &scope)
+                .into_iter()
+                .filter(|path| acl::allows(path, Permission::Read))
+                .filter_map(|path| match_file(&path, &words))
+                .take(limit)
+                .collect::<Vec<_>>()
+        })
+    })
+    .await
+    .unwrap();
+
+    AxumJson(results).into_response()
+}
+
+// The index only narrows things down; the file itself is read to confirm
+// every word occurs and to pull out the lines around the hits
+fn match_file(path: &Path, words: &[String]) -> Option<ContentMatch> {
+    let size = std::fs::metadata(path).ok()?.len();
+    let text = read_text(path, size)?;
+
+    let mut seen = vec![false; words.len()];
+    let mut lines = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let ranges = find_words(line, words, &mut seen);
+        if !ranges.is_empty() && lines.len() < SNIPPETS_PER_FILE {
+            lines.push(snippet(number + 1, line, ranges));
+        }
+    }
+
+    if !seen.iter().all(|&s| s) {
+        return None;
+    }
+    Some(ContentMatch { path: sandbox::relative_path(path), lines })
+}
+
+// Character ranges in `line` where any of `words` occur, case-insensitively
+fn find_words(line: &str, words: &[String], seen: &mut [bool]) -> Vec<[usize; 2]> {
+    let chars: Vec<char> = line.chars().collect();
+    let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+
+    let mut ranges = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        let needle: Vec<char> = word.chars().collect();
+        if needle.is_empty() || needle.len() > lower.len() {
+            continue;
+        }
+        let mut start = 0;
+        while start + needle.len() <= lower.len() {
+            if lower[start..start + needle.len()] == needle[..] {
+                ranges.push([start, start + needle.len()]);
+                seen[i] = true;
+                start += needle.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+    ranges.sort();
+    ranges
+}
+
+// Long lines are cut down to a window around the first hit
+fn snippet(line: usize, text: &str, highlights: Vec<[usize; 2]>) -> LineMatch {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= SNIPPET_CHARS {
+        return LineMatch { line, text: text.to_string(), highlights };
+    }
+
+    let start = highlights[0][0].saturating_sub(SNIPPET_CHARS / 3);
+    let end = (start + SNIPPET_CHARS).min(chars.len());
+    let highlights = highlights
+        .into_iter()
+        .filter(|[s, e]| *s >= start && *e <= end)
+        .map(|[s, e]| [s - start, e - start])
+        .collect();
+    LineMatch { line, text: chars[start..end].iter().collect(), highlights }
+}
@@ -0,0 +1,329 @@
+// Server-side copies of files and folder trees. Copies run as background jobs
+// that can be polled for progress and cancelled.
+use axum::{
+    extract::{Json, Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, FileTimes},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    acl::{self, Permission},
+    auth,
+    content_index::ContentIndex,
+    disk_usage, fs_ops, quota, resolve_path,
+    sandbox::{self, resolve_writable},
+};
+
+// Finished jobs stay around this long so clients can read the outcome
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+const COPY_BUFFER: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ConflictPolicy {
+    // Refuse to start if the destination already exists
+    #[default]
+    Fail,
+    // Merge into an existing folder, leaving files that already exist alone
+    Skip,
+    // Merge into an existing folder, replacing files that already exist
+    Overwrite,
+}
+
+#[derive(Deserialize)]
+struct CopyReq {
+    from: String,
+    to: String,
+    volume: Option<String>,
+    // Volume to copy into, the source volume by default
+    to_volume: Option<String>,
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+#[derive(Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Scanning,
+    Copying,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Serialize)]
+struct CopyProgress {
+    id: String,
+    state: JobState,
+    files_total: u64,
+    bytes_total: u64,
+    files_copied: u64,
+    bytes_copied: u64,
+    // Paths, relative to the copied tree, that already existed at the destination
+    conflicts: Vec<String>,
+    error: Option<String>,
+    #[serde(skip)]
+    finished_at: Option<SystemTime>,
+}
+
+struct CopyJob {
+    // Who started it; `None` with auth turned off
+    owner: Option<String>,
+    progress: Mutex<CopyProgress>,
+    cancel: AtomicBool,
+}
+
+impl CopyJob {
+    fn update(&self, f: impl FnOnce(&mut CopyProgress)) {
+        f(&mut self.progress.lock().unwrap());
+    }
+
+    fn check_cancelled(&self) -> io::Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(fs_ops::cancelled());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct CopyState {
+    jobs: Arc<Mutex<HashMap<String, Arc<CopyJob>>>>,
+    index: ContentIndex,
+}
+
+pub fn router(index: ContentIndex) -> Router {
+    Router::new()
+        .route("/", post(start_copy))
+        .route("/:id", get(copy_status).delete(cancel_copy))
+        .with_state(CopyState { jobs: Default::default(), index })
+}
+
+async fn start_copy(State(state): State<CopyState>, Json(payload): Json<CopyReq>) -> impl IntoResponse {
+    let to_volume = payload.to_volume.as_deref().or(payload.volume.as_deref());
+    let (from, to) = match (
+        resolve_path(payload.volume.as_deref(), Some(payload.from)),
+        resolve_writable(to_volume, Some(payload.to)),
+    ) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+    if sandbox::relative_path(&to).is_empty() {
+        return (StatusCode::BAD_REQUEST, "Cannot copy over the volume root").into_response();
+    }
+    if let Err(e) = acl::check_tree(&from, Permission::Read).and_then(|_| acl::check_tree(&to, Permission::Write)) {
+        return e.into_response();
+    }
+
+    if !from.exists() {
+        return (StatusCode::NOT_FOUND, "Source not found").into_response();
+    }
+    if from.is_dir() && to.starts_with(&from) {
+        return (StatusCode::BAD_REQUEST, "Cannot copy a folder into itself").into_response();
+    }
+    if !to.parent().is_some_and(|p| p.is_dir()) {
+        return (StatusCode::NOT_FOUND, "Destination folder not found").into_response();
+    }
+    if to.exists() {
+        if payload.on_conflict == ConflictPolicy::Fail {
+            return (StatusCode::CONFLICT, "Destination already exists").into_response();
+        }
+        if from.is_dir() != to.is_dir() {
+            return (StatusCode::CONFLICT, "Destination exists with a different type").into_response();
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let job = Arc::new(CopyJob {
+        owner: auth::current_user().map(|u| u.username),
+        progress: Mutex::new(CopyProgress {
+            id: id.clone(),
+            state: JobState::Scanning,
+            files_total: 0,
+            bytes_total: 0,
+            files_copied: 0,
+            bytes_copied: 0,
+            conflicts: Vec::new(),
+            error: None,
+            finished_at: None,
+        }),
+        cancel: AtomicBool::new(false),
+    });
+
+    {
+        let mut jobs = state.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            let finished_at = job.progress.lock().unwrap().finished_at;
+            finished_at.is_none_or(|t| t.elapsed().unwrap_or_default() < FINISHED_JOB_TTL)
+        });
+        jobs.insert(id.clone(), job.clone());
+    }
+
+    let policy = payload.on_conflict;
+    let index = state.index.clone();
+    let limit = quota::remaining().await;
+    tokio::task::spawn_blocking(move || {
+        let result = run_copy(&job, &from, &to, policy, limit);
+        // Even a cancelled or failed copy may have written some files
+        quota::invalidate(&to);
+        disk_usage::invalidate(&to);
+        index.refresh(to);
+        job.update(|p| {
+            p.finished_at = Some(SystemTime::now());
+            p.state = match result {
+                Ok(()) => JobState::Completed,
+                Err(e) if fs_ops::is_cancelled(&e) => JobState::Cancelled,
+                Err(e) => {
+                    p.error = Some(e.to_string());
+                    JobState::Failed
+                }
+            };
+        });
+    });
+
+    let progress = state.jobs.lock().unwrap()[&id].progress.lock().unwrap().clone();
+    (StatusCode::ACCEPTED, AxumJson(progress)).into_response()
+}
+
+async fn copy_status(State(state): State<CopyState>, UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    let job = state.jobs.lock().unwrap().get(&id).cloned();
+    let Some(job) = job.filter(|job| auth::owns_job(job.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Copy job not found").into_response();
+    };
+    let progress = job.progress.lock().unwrap().clone();
+    AxumJson(progress).into_response()
+}
+
+async fn cancel_copy(State(state): State<CopyState>, UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    let job = state.jobs.lock().unwrap().get(&id).cloned();
+    let Some(job) = job.filter(|job| auth::owns_job(job.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Copy job not found").into_response();
+    };
+    job.cancel.store(true, Ordering::Relaxed);
+    (StatusCode::ACCEPTED, "Cancelling").into_response()
+}
+
+// `limit` is what is left of the user's quota, checked once the size is known
+fn run_copy(job: &CopyJob, from: &Path, to: &Path, policy: ConflictPolicy, limit: Option<u64>) -> io::Result<()> {
+    // Size the tree first so progress can be reported against a total
+    let mut files_total = 0;
+    let mut bytes_total = 0;
+    for entry in WalkDir::new(from).into_iter().filter_entry(|e| !sandbox::is_reserved(e.path())) {
+        job.check_cancelled()?;
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files_total += 1;
+            bytes_total += entry.metadata()?.len();
+        }
+    }
+    job.update(|p| {
+        p.files_total = files_total;
+        p.bytes_total = bytes_total;
+    });
+    if limit.is_some_and(|limit| bytes_total > limit) {
+        return Err(io::Error::other("Storage quota exceeded"));
+    }
+    job.update(|p| p.state = JobState::Copying);
+
+    // Folder times are restored last, since copying into them touches their mtime
+    let mut folders: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+    for entry in WalkDir::new(from).into_iter().filter_entry(|e| !sandbox::is_reserved(e.path())) {
+        job.check_cancelled()?;
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(from).unwrap();
+        let target = if relative.as_os_str().is_empty() { to.to_path_buf() } else { to.join(relative) };
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+            folders.push((entry.path().to_path_buf(), target));
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        if target.exists() {
+            let name = relative_name(from, entry.path());
+            job.update(|p| p.conflicts.push(name));
+            if policy == ConflictPolicy::Skip {
+                job.update(|p| {
+                    p.files_copied += 1;
+                    p.bytes_copied += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                });
+                continue;
+            }
+        }
+
+        copy_file(job, entry.path(), &target)?;
+        job.update(|p| p.files_copied += 1);
+    }
+
+    for (source, target) in folders.iter().rev() {
+        let _ = copy_times(source, target);
+    }
+    Ok(())
+}
+
+fn copy_file(job: &CopyJob, from: &Path, to: &Path) -> io::Result<()> {
+    // Written under a temporary name, so an overwritten file is only replaced
+    // once its copy is complete and a cancelled copy leaves nothing behind
+    let staging = fs_ops::staging_path(to);
+    let result = (|| {
+        let mut input = File::open(from)?;
+        let mut output = File::create(&staging)?;
+        let mut buf = vec![0; COPY_BUFFER];
+        loop {
+            job.check_cancelled()?;
+            let n = input.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            output.write_all(&buf[..n])?;
+            job.update(|p| p.bytes_copied += n as u64);
+        }
+        output.set_permissions(input.metadata()?.permissions())?;
+        drop(output);
+        copy_times(from, &staging)?;
+        std::fs::rename(&staging, to)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&staging);
+    }
+    result
+}
+
+fn copy_times(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = std::fs::metadata(from)?;
+    let mut times = FileTimes::new().set_modified(metadata.modified()?);
+    if let Ok(accessed) = metadata.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    // Opening a folder this way works on unix; elsewhere folder times are best effort
+    File::options().write(to.is_file()).read(true).open(to)?.set_times(times)
+}
+
+fn relative_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    if relative.as_os_str().is_empty() {
+        root.file_name().unwrap_or_default().to_string_lossy().to_string()
+    } else {
+        relative.to_string_lossy().replace('\\', "/")
+    }
+}
@@ -0,0 +1,435 @@
+// What is taking up space under a folder. A scan walks the tree once on a
+// blocking thread, adding up every file into its folders the way `du` does,
+// and keeps the largest folders and files. Scans can be polled for progress
+// and cancelled. A finished scan is reused for the same folder until a
+// request changes something inside it, or it gets too old to trust changes
+// made behind the server's back.
+use axum::{
+    extract::{Json, Path as UrlPath, Query},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    acl::{self, Permission},
+    auth, fs_ops, resolve_path, sandbox,
+};
+
+// How long a finished scan is reused
+const CACHE_TTL: Duration = Duration::from_secs(15 * 60);
+// Largest folders and files kept per scan; requests pick up to this many
+const MAX_TOP: usize = 100;
+const DEFAULT_TOP: usize = 20;
+
+static SCANS: LazyLock<Mutex<HashMap<String, Arc<Scan>>>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ScanState {
+    Scanning,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone)]
+struct Sized {
+    path: PathBuf,
+    apparent: u64,
+    allocated: u64,
+    // Files below a folder; 0 for files
+    files: u64,
+}
+
+// Ordered by allocated size, so heaps keep what really takes up the disk
+impl PartialEq for Sized {
+    fn eq(&self, other: &Self) -> bool {
+        self.allocated == other.allocated
+    }
+}
+
+impl Eq for Sized {}
+
+impl PartialOrd for Sized {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sized {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.allocated.cmp(&other.allocated)
+    }
+}
+
+struct Tree {
+    files: u64,
+    dirs: u64,
+    apparent: u64,
+    allocated: u64,
+    // Largest first
+    largest_dirs: Vec<Sized>,
+    largest_files: Vec<Sized>,
+}
+
+struct Progress {
+    state: ScanState,
+    files_scanned: u64,
+    dirs_scanned: u64,
+    bytes_scanned: u64,
+    error: Option<String>,
+    tree: Option<Arc<Tree>>,
+    finished_at: Option<SystemTime>,
+}
+
+struct Scan {
+    id: String,
+    // Who started it; `None` with auth turned off
+    owner: Option<String>,
+    root: PathBuf,
+    progress: Mutex<Progress>,
+    cancel: AtomicBool,
+    // Set once something under `root` changed after the scan started
+    stale: AtomicBool,
+}
+
+impl Scan {
+    fn update(&self, f: impl FnOnce(&mut Progress)) {
+        f(&mut self.progress.lock().unwrap());
+    }
+
+    fn check_cancelled(&self) -> io::Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(fs_ops::cancelled());
+        }
+        Ok(())
+    }
+
+    // Whether a new request for the same folder can use this scan
+    fn reusable(&self) -> bool {
+        if self.stale.load(Ordering::Relaxed) {
+            return false;
+        }
+        let progress = self.progress.lock().unwrap();
+        match (progress.state, progress.finished_at) {
+            (ScanState::Scanning, _) => true,
+            (ScanState::Completed, Some(t)) => t.elapsed().unwrap_or_default() < CACHE_TTL,
+            _ => false,
+        }
+    }
+}
+
+// Marks scans that `path` was inside of, or that were inside `path`, as out
+// of date. Called by every handler that writes, moves or deletes.
+pub fn invalidate(path: &Path) {
+    for scan in SCANS.lock().unwrap().values() {
+        if path.starts_with(&scan.root) || scan.root.starts_with(path) {
+            scan.stale.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", post(start_scan))
+        .route("/:id", get(scan_status).delete(cancel_scan))
+}
+
+#[derive(Deserialize)]
+struct ScanReq {
+    path: Option<String>,
+    volume: Option<String>,
+    // Start over even when a recent scan of the folder exists
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    // How many of the largest folders and files to return
+    top: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SizeEntry {
+    path: String,
+    apparent_bytes: u64,
+    allocated_bytes: u64,
+    files: u64,
+}
+
+#[derive(Serialize)]
+struct UsageTree {
+    files: u64,
+    dirs: u64,
+    // Sum of file lengths
+    apparent_bytes: u64,
+    // Blocks actually taken on disk, less for sparse files and more for many small ones
+    allocated_bytes: u64,
+    largest_dirs: Vec<SizeEntry>,
+    largest_files: Vec<SizeEntry>,
+}
+
+#[derive(Serialize)]
+struct ScanStatus {
+    id: String,
+    path: String,
+    state: ScanState,
+    files_scanned: u64,
+    dirs_scanned: u64,
+    bytes_scanned: u64,
+    error: Option<String>,
+    // Unix seconds
+    finished_at: Option<u64>,
+    // Something changed since; start a new scan for current numbers
+    stale: bool,
+    // Once completed
+    result: Option<UsageTree>,
+}
+
+impl Scan {
+    // What the caller gets to see: entries they can't read are left out of
+    // the largest lists, though they still count towards the totals
+    fn status(&self, top: usize) -> ScanStatus {
+        let progress = self.progress.lock().unwrap();
+        let entries = |list: &[Sized]| -> Vec<SizeEntry> {
+            list.iter()
+                .filter(|s| acl::allows(&s.path, Permission::Read))
+                .take(top)
+                .map(|s| SizeEntry {
+                    path: sandbox::relative_path(&s.path),
+                    apparent_bytes: s.apparent,
+                    allocated_bytes: s.allocated,
+                    files: s.files,
+                })
+                .collect()
+        };
+        ScanStatus {
+            id: self.id.clone(),
+            path: sandbox::relative_path(&self.root),
+            state: progress.state,
+            files_scanned: progress.files_scanned,
+            dirs_scanned: progress.dirs_scanned,
+            bytes_scanned: progress.bytes_scanned,
+            error: progress.error.clone(),
+            finished_at: progress
+                .finished_at
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            stale: self.stale.load(Ordering::Relaxed),
+            result: progress.tree.as_ref().map(|tree| UsageTree {
+                files: tree.files,
+                dirs: tree.dirs,
+                apparent_bytes: tree.apparent,
+                allocated_bytes: tree.allocated,
+                largest_dirs: entries(&tree.largest_dirs),
+                largest_files: entries(&tree.largest_files),
+            }),
+        }
+    }
+}
+
+async fn start_scan(Query(query): Query<TopQuery>, Json(payload): Json<ScanReq>) -> impl IntoResponse {
+    let root = match resolve_path(payload.volume.as_deref(), payload.path) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&root, Permission::Read) {
+        return e.into_response();
+    }
+    if !root.is_dir() {
+        return (StatusCode::NOT_FOUND, "Folder not found").into_response();
+    }
+    let top = query.top.unwrap_or(DEFAULT_TOP).min(MAX_TOP);
+    let owner = auth::current_user().map(|u| u.username);
+
+    let scan = {
+        let mut scans = SCANS.lock().unwrap();
+        scans.retain(|_, scan| {
+            let finished_at = scan.progress.lock().unwrap().finished_at;
+            finished_at.is_none_or(|t| t.elapsed().unwrap_or_default() < CACHE_TTL)
+        });
+        // Only the user who started a scan can follow it, so only they reuse it
+        let existing = scans.values().find(|s| s.root == root && s.owner == owner && s.reusable()).cloned();
+        match existing.filter(|_| !payload.refresh) {
+            Some(scan) => scan,
+            None => {
+                let scan = Arc::new(Scan {
+                    id: uuid::Uuid::new_v4().simple().to_string(),
+                    owner,
+                    root: root.clone(),
+                    progress: Mutex::new(Progress {
+                        state: ScanState::Scanning,
+                        files_scanned: 0,
+                        dirs_scanned: 0,
+                        bytes_scanned: 0,
+                        error: None,
+                        tree: None,
+                        finished_at: None,
+                    }),
+                    cancel: AtomicBool::new(false),
+                    stale: AtomicBool::new(false),
+                });
+                scans.insert(scan.id.clone(), scan.clone());
+                let job = scan.clone();
+                tokio::task::spawn_blocking(move || {
+                    let result = measure(&job);
+                    job.update(|p| {
+                        p.finished_at = Some(SystemTime::now());
+                        p.state = match result {
+                            Ok(tree) => {
+                                p.tree = Some(Arc::new(tree));
+                                ScanState::Completed
+                            }
+                            Err(e) if fs_ops::is_cancelled(&e) => ScanState::Cancelled,
+                            Err(e) => {
+                                p.error = Some(e.to_string());
+                                ScanState::Failed
+                            }
+                        };
+                    });
+                });
+                scan
+            }
+        }
+    };
+
+    let status = scan.status(top);
+    let code = if status.state == ScanState::Completed { StatusCode::OK } else { StatusCode::ACCEPTED };
+    (code, AxumJson(status)).into_response()
+}
+
+async fn scan_status(UrlPath(id): UrlPath<String>, Query(query): Query<TopQuery>) -> impl IntoResponse {
+    let scan = SCANS.lock().unwrap().get(&id).cloned();
+    let Some(scan) = scan.filter(|scan| auth::owns_job(scan.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Scan not found").into_response();
+    };
+    AxumJson(scan.status(query.top.unwrap_or(DEFAULT_TOP).min(MAX_TOP))).into_response()
+}
+
+async fn cancel_scan(UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    let scan = SCANS.lock().unwrap().get(&id).cloned();
+    let Some(scan) = scan.filter(|scan| auth::owns_job(scan.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Scan not found").into_response();
+    };
+    scan.cancel.store(true, Ordering::Relaxed);
+    (StatusCode::ACCEPTED, "Cancelling").into_response()
+}
+
+// Walks the tree depth first, keeping a running total for each folder on the
+// way down to the current entry. A folder's total is final once the walk
+// leaves it, and is then added to its parent.
+fn measure(scan: &Scan) -> io::Result<Tree> {
+    let mut open: Vec<Sized> = vec![Sized { path: scan.root.clone(), apparent: 0, allocated: 0, files: 0 }];
+    let mut dirs: BinaryHeap<Reverse<Sized>> = BinaryHeap::new();
+    let mut files: BinaryHeap<Reverse<Sized>> = BinaryHeap::new();
+    let mut dir_count = 0;
+    // Hard linked files are counted once, like `du` does
+    let mut seen_links = HashSet::new();
+
+    let close = |open: &mut Vec<Sized>, dirs: &mut BinaryHeap<Reverse<Sized>>| {
+        let done = open.pop().unwrap();
+        let parent = open.last_mut().unwrap();
+        parent.apparent += done.apparent;
+        parent.allocated += done.allocated;
+        parent.files += done.files;
+        keep_largest(dirs, done);
+    };
+
+    let entries = WalkDir::new(&scan.root)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| !sandbox::is_reserved(e.path()));
+    for entry in entries {
+        scan.check_cancelled()?;
+        // Unreadable folders are skipped rather than ending the scan
+        let Ok(entry) = entry else { continue };
+        while open.len() > entry.depth() {
+            close(&mut open, &mut dirs);
+        }
+
+        if entry.file_type().is_dir() {
+            dir_count += 1;
+            open.push(Sized { path: entry.path().to_path_buf(), apparent: 0, allocated: 0, files: 0 });
+            scan.update(|p| p.dirs_scanned += 1);
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else { continue };
+        if let Some(id) = fs_ops::link_id(&metadata)
+            && !seen_links.insert(id)
+        {
+            continue;
+        }
+
+        let file = Sized {
+            path: entry.path().to_path_buf(),
+            apparent: metadata.len(),
+            allocated: allocated(&metadata),
+            files: 0,
+        };
+        let current = open.last_mut().unwrap();
+        current.apparent += file.apparent;
+        current.allocated += file.allocated;
+        current.files += 1;
+        scan.update(|p| {
+            p.files_scanned += 1;
+            p.bytes_scanned += file.apparent;
+        });
+        keep_largest(&mut files, file);
+    }
+    while open.len() > 1 {
+        close(&mut open, &mut dirs);
+    }
+
+    let root = open.pop().unwrap();
+    let sorted = |heap: BinaryHeap<Reverse<Sized>>| heap.into_sorted_vec().into_iter().map(|Reverse(s)| s).collect();
+    Ok(Tree {
+        files: root.files,
+        dirs: dir_count,
+        apparent: root.apparent,
+        allocated: root.allocated,
+        largest_dirs: sorted(dirs),
+        largest_files: sorted(files),
+    })
+}
+
+// Keeps the `MAX_TOP` largest entries; the heap's top is the smallest kept
+fn keep_largest(heap: &mut BinaryHeap<Reverse<Sized>>, entry: Sized) {
+    if heap.len() < MAX_TOP {
+        heap.push(Reverse(entry));
+    } else if heap.peek().is_some_and(|Reverse(smallest)| entry.allocated > smallest.allocated) {
+        heap.pop();
+        heap.push(Reverse(entry));
+    }
+}
+
+#[cfg(unix)]
+fn allocated(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // `st_blocks` is always in 512 byte units
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
@@ -0,0 +1,531 @@
+// Drop links. A drop is a random token that lets anyone holding it upload
+// into one folder without an account, and without seeing what is already
+// there: nothing can be listed or downloaded through it, and a file whose
+// name is taken is stored under a free name instead of replacing anything.
+// Uploads run as the user who made the link, so their access rules and
+// quota apply.
+use axum::{
+    extract::{multipart::Field, Json, Multipart, Path as UrlPath, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json as AxumJson, Response},
+    routing::{delete, get},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    acl::{self, Permission},
+    auth::{self, Auth, CurrentUser},
+    config,
+    content_index::ContentIndex,
+    disk_usage, fs_ops,
+    links::{self, Link},
+    quota,
+    sandbox::{self, resolve_writable},
+    trash, upload,
+};
+
+static DROPS: LazyLock<Mutex<Vec<DropLink>>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DropLink {
+    token: String,
+    // Who created it; `None` with auth turned off
+    owner: Option<String>,
+    volume: String,
+    // Folder the files go to, relative to the volume root
+    path: String,
+    // Unix seconds
+    created_at: u64,
+    expires_at: Option<u64>,
+    max_files: Option<u64>,
+    max_file_bytes: Option<u64>,
+    max_total_bytes: Option<u64>,
+    #[serde(default)]
+    files_received: u64,
+    #[serde(default)]
+    bytes_received: u64,
+    // Set aside for files still being received, so parallel uploads through
+    // the link can't go over its limits together
+    #[serde(skip)]
+    files_reserved: u64,
+    #[serde(skip)]
+    bytes_reserved: u64,
+}
+
+impl Link for DropLink {
+    // Expired, or every file or byte it allows has been used
+    fn closed(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= auth::unix_now())
+            || self.max_files.is_some_and(|max| self.files_received >= max)
+            || self.max_total_bytes.is_some_and(|max| self.bytes_received >= max)
+    }
+}
+
+impl DropLink {
+    fn bytes_left(&self) -> Option<u64> {
+        self.max_total_bytes.map(|max| max.saturating_sub(self.bytes_received))
+    }
+
+    // The most the next file may be, with what uploads in progress have set aside
+    fn file_limit(&self) -> Option<u64> {
+        let free = self.bytes_left().map(|left| left.saturating_sub(self.bytes_reserved));
+        match (self.max_file_bytes, free) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.closed()
+            || self.max_files.is_some_and(|max| self.files_received + self.files_reserved >= max)
+            || self.file_limit() == Some(0)
+    }
+
+    fn managed_by(&self, user: Option<&CurrentUser>) -> bool {
+        match user {
+            Some(user) => user.is_admin || self.owner.as_deref() == Some(user.username.as_str()),
+            None => true,
+        }
+    }
+}
+
+// Reads the drops file, if there is one yet
+pub fn load() -> io::Result<()> {
+    *DROPS.lock().unwrap() = fs_ops::read_json_or_default(&config::get().links.drops_file)?;
+    Ok(())
+}
+
+fn save(drops: &[DropLink]) -> io::Result<()> {
+    fs_ops::write_json_atomic(&config::get().links.drops_file, drops)
+}
+
+#[derive(Clone)]
+struct DropState {
+    index: ContentIndex,
+    // To act as the link's owner; `None` with auth turned off
+    auth: Option<Auth>,
+}
+
+// Managing drops, for signed-in users
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(list_drops).post(create_drop))
+        .route("/:token", delete(revoke_drop))
+}
+
+// What people holding a link can reach
+pub fn public_router(index: ContentIndex, auth: Option<Auth>) -> Router {
+    Router::new()
+        .route("/:token", get(drop_info).post(drop_upload).layer(upload::body_limit()))
+        .with_state(DropState { index, auth })
+}
+
+#[derive(Serialize)]
+struct DropInfo {
+    token: String,
+    url: String,
+    owner: Option<String>,
+    volume: String,
+    // Relative to the caller's root
+    path: String,
+    created_at: u64,
+    expires_at: Option<u64>,
+    max_files: Option<u64>,
+    max_file_bytes: Option<u64>,
+    max_total_bytes: Option<u64>,
+    files_received: u64,
+    bytes_received: u64,
+}
+
+impl From<&DropLink> for DropInfo {
+    fn from(drop: &DropLink) -> Self {
+        let path = match sandbox::volume(Some(&drop.volume)) {
+            Ok(volume) => sandbox::relative_path(&volume.path.join(&drop.path)),
+            Err(_) => drop.path.clone(),
+        };
+        DropInfo {
+            token: drop.token.clone(),
+            url: format!("/d/{}", drop.token),
+            owner: drop.owner.clone(),
+            volume: drop.volume.clone(),
+            path,
+            created_at: drop.created_at,
+            expires_at: drop.expires_at,
+            max_files: drop.max_files,
+            max_file_bytes: drop.max_file_bytes,
+            max_total_bytes: drop.max_total_bytes,
+            files_received: drop.files_received,
+            bytes_received: drop.bytes_received,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateDropReq {
+    path: String,
+    volume: Option<String>,
+    // Seconds from now; the link never expires when absent
+    expires_in_secs: Option<u64>,
+    max_files: Option<u64>,
+    max_file_bytes: Option<u64>,
+    max_total_bytes: Option<u64>,
+}
+
+async fn create_drop(user: Option<CurrentUser>, Json(payload): Json<CreateDropReq>) -> impl IntoResponse {
+    let path = match resolve_writable(payload.volume.as_deref(), Some(payload.path)) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&path, Permission::Write) {
+        return e.into_response();
+    }
+    if !path.is_dir() {
+        return (StatusCode::NOT_FOUND, "Folder not found").into_response();
+    }
+    let limits = [payload.expires_in_secs, payload.max_files, payload.max_file_bytes, payload.max_total_bytes];
+    if limits.contains(&Some(0)) {
+        return (StatusCode::BAD_REQUEST, "Expiry and limits must be greater than 0").into_response();
+    }
+    let Some((volume, relative)) = sandbox::real_volume_relative(&path) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Could not resolve path").into_response();
+    };
+
+    let now = auth::unix_now();
+    let drop = DropLink {
+        token: auth::random_hex(16),
+        owner: user.map(|u| u.username),
+        volume: volume.name.clone(),
+        path: relative,
+        created_at: now,
+        expires_at: payload.expires_in_secs.map(|secs| now.saturating_add(secs)),
+        max_files: payload.max_files,
+        max_file_bytes: payload.max_file_bytes,
+        max_total_bytes: payload.max_total_bytes,
+        files_received: 0,
+        bytes_received: 0,
+        files_reserved: 0,
+        bytes_reserved: 0,
+    };
+
+    let info = DropInfo::from(&drop);
+    match links::add(&mut DROPS.lock().unwrap(), drop, &config::get().links.drops_file) {
+        Ok(()) => (StatusCode::CREATED, AxumJson(info)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// The caller's own drops; admins see everyone's
+async fn list_drops(user: Option<CurrentUser>) -> impl IntoResponse {
+    let mut drops: Vec<DropInfo> = DROPS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|d| !d.closed() && d.managed_by(user.as_ref()))
+        .map(DropInfo::from)
+        .collect();
+    drops.sort_by_key(|d| std::cmp::Reverse(d.created_at));
+    AxumJson(drops)
+}
+
+async fn revoke_drop(user: Option<CurrentUser>, UrlPath(token): UrlPath<String>) -> impl IntoResponse {
+    let mut drops = DROPS.lock().unwrap();
+    let Some(position) = drops.iter().position(|d| d.token == token && d.managed_by(user.as_ref())) else {
+        return (StatusCode::NOT_FOUND, "No such drop").into_response();
+    };
+    let removed = drops.remove(position);
+    match save(&drops) {
+        Ok(()) => (StatusCode::OK, "Drop revoked").into_response(),
+        Err(e) => {
+            drops.insert(position, removed);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+fn open_drop(token: &str) -> Result<DropLink, (StatusCode, String)> {
+    let drop = DROPS.lock().unwrap().iter().find(|d| d.token == token).cloned();
+    match drop {
+        None => Err((StatusCode::NOT_FOUND, "No such drop".to_string())),
+        Some(drop) if drop.closed() => Err((StatusCode::GONE, "This link no longer accepts files".to_string())),
+        Some(drop) => Ok(drop),
+    }
+}
+
+// A file slot and bytes set aside on a link for a file being received. They
+// are given back on drop, also when the client goes away mid-upload.
+struct Reservation {
+    token: String,
+    // When the link limits them
+    bytes: Option<u64>,
+}
+
+impl Reservation {
+    // Sets a slot and up to `at_most` bytes aside for the next file
+    fn take(token: &str, at_most: u64) -> Result<Self, (StatusCode, String)> {
+        let mut drops = DROPS.lock().unwrap();
+        let Some(drop) = drops.iter_mut().find(|d| d.token == token) else {
+            return Err((StatusCode::GONE, "This link no longer accepts files".to_string()));
+        };
+        if drop.full() {
+            return Err((StatusCode::FORBIDDEN, "This link accepts no more files".to_string()));
+        }
+        let bytes = drop.file_limit().map(|limit| limit.min(at_most));
+        drop.files_reserved += 1;
+        drop.bytes_reserved += bytes.unwrap_or(0);
+        Ok(Reservation { token: token.to_string(), bytes })
+    }
+
+    // Counts the stored file against the link
+    fn settle(self, written: u64) {
+        let mut drops = DROPS.lock().unwrap();
+        let Some(drop) = drops.iter_mut().find(|d| d.token == self.token) else {
+            return;
+        };
+        drop.files_received += 1;
+        drop.bytes_received += written;
+        if let Err(e) = save(&drops) {
+            tracing::warn!("could not record drop upload: {}", e);
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut drops = DROPS.lock().unwrap();
+        if let Some(drop) = drops.iter_mut().find(|d| d.token == self.token) {
+            drop.files_reserved -= 1;
+            drop.bytes_reserved -= self.bytes.unwrap_or(0);
+        }
+    }
+}
+
+// Only what an uploader needs; the folder itself stays hidden
+#[derive(Serialize)]
+struct PublicDropInfo {
+    expires_at: Option<u64>,
+    files_left: Option<u64>,
+    max_file_bytes: Option<u64>,
+    bytes_left: Option<u64>,
+}
+
+async fn drop_info(UrlPath(token): UrlPath<String>) -> Response {
+    match open_drop(&token) {
+        Ok(drop) => AxumJson(PublicDropInfo {
+            expires_at: drop.expires_at,
+            files_left: drop.max_files.map(|max| max.saturating_sub(drop.files_received)),
+            max_file_bytes: drop.max_file_bytes,
+            bytes_left: drop.bytes_left(),
+        })
+        .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn drop_upload(
+    State(state): State<DropState>,
+    UrlPath(token): UrlPath<String>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Response {
+    let drop = match open_drop(&token) {
+        Ok(d) => d,
+        Err(e) => return e.into_response(),
+    };
+    let owner = match (&state.auth, &drop.owner) {
+        (Some(auth), Some(owner)) => match auth.account(owner) {
+            Some(user) => Some(user),
+            None => return (StatusCode::GONE, "This link no longer accepts files").into_response(),
+        },
+        _ => None,
+    };
+    // Resolved from the volume root, before acting as the owner
+    let target_dir = match resolve_writable(Some(&drop.volume), Some(drop.path.clone())) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if !target_dir.is_dir() {
+        return (StatusCode::GONE, "This link no longer accepts files").into_response();
+    }
+
+    auth::run_as(owner, receive(state, drop, target_dir, headers, multipart)).await
+}
+
+async fn receive(
+    state: DropState,
+    drop: DropLink,
+    target_dir: PathBuf,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Err(e) = acl::check(&target_dir, Permission::Write) {
+        return e.into_response();
+    }
+    let content_length: u64 = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let payload = content_length.saturating_sub(crate::MULTIPART_OVERHEAD);
+    if drop.bytes_left().is_some_and(|left| payload > left) {
+        return too_large();
+    }
+    if let Err(e) = quota::check(payload).await {
+        return e.into_response();
+    }
+
+    // No file in the request can be larger than the request itself
+    let at_most = if content_length > 0 { content_length } else { u64::MAX };
+    let mut received = 0;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        };
+        let Some(file_name) = upload::field_file_name(&field) else {
+            continue;
+        };
+        let reservation = match Reservation::take(&drop.token, at_most) {
+            Ok(r) => r,
+            Err((status, message)) => {
+                let message = format!("{}; {} file(s) were received", message, received);
+                return (status, message).into_response();
+            }
+        };
+
+        let (stored, written) = match store(field, &target_dir.join(&file_name), reservation.bytes).await {
+            Ok(stored) => stored,
+            Err(e) => return e,
+        };
+        reservation.settle(written);
+        quota::record(&stored, written as i64);
+        disk_usage::invalidate(&stored);
+        state.index.refresh(stored);
+        received += 1;
+    }
+
+    // Names aren't echoed back: a renamed file would give away what was there
+    (StatusCode::OK, format!("Received {} file(s)", received)).into_response()
+}
+
+// Receives one file within the link's and the owner's limits, and gives it a
+// free name next to `target`
+async fn store(field: Field<'_>, target: &Path, link_limit: Option<u64>) -> Result<(PathBuf, u64), Response> {
+    let quota_limit = quota::remaining().await;
+    let limit = match (quota_limit, link_limit) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    // Whether going over `limit` means the link's cap rather than the owner's quota
+    let link_bound = link_limit.is_some_and(|l| quota_limit.is_none_or(|q| l <= q));
+    let (temp, written) = match upload::receive_field(field, target, limit).await {
+        Ok(r) => r,
+        Err((StatusCode::INSUFFICIENT_STORAGE, _)) if link_bound => return Err(too_large()),
+        Err(e) => return Err(e.into_response()),
+    };
+
+    // Another request may take a name between looking for a free one and
+    // using it, in which case the next free one is tried
+    let mut name = target.to_path_buf();
+    loop {
+        match temp.persist_new(&name).await {
+            Ok(()) => return Ok((name, written)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => name = trash::free_name(target),
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()),
+        }
+    }
+}
+
+fn too_large() -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, "More than this link accepts").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::FromRequest, http::Request};
+
+    // A fresh folder with a drop link to it, created with `limits`
+    async fn drop_into(folder: &str, limits: serde_json::Value) -> (String, PathBuf) {
+        let dir = config::for_tests().volumes[0].path.join(folder);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut request = serde_json::json!({"path": folder});
+        request.as_object_mut().unwrap().extend(limits.as_object().unwrap().clone());
+        let response = create_drop(None, Json(serde_json::from_value(request).unwrap())).await.into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (info["token"].as_str().unwrap().to_string(), dir)
+    }
+
+    async fn upload(token: &str, files: &[(&str, &str)]) -> Response {
+        let mut body = String::new();
+        for (name, content) in files {
+            body.push_str(&format!(
+                "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                name, content
+            ));
+        }
+        body.push_str("--X--\r\n");
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+            .body(Body::from(body))
+            .unwrap();
+        let multipart = Multipart::from_request(request, &()).await.unwrap();
+        let state = DropState { index: ContentIndex::default(), auth: None };
+        drop_upload(State(state), UrlPath(token.to_string()), HeaderMap::new(), multipart).await
+    }
+
+    #[tokio::test]
+    async fn taken_names_are_never_replaced() {
+        let (token, dir) = drop_into("drop_names", serde_json::json!({})).await;
+        std::fs::write(dir.join("a.txt"), "old").unwrap();
+        assert_eq!(upload(&token, &[("a.txt", "new"), ("a.txt", "newer")]).await.status(), StatusCode::OK);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "old");
+        assert_eq!(std::fs::read_to_string(dir.join("a (1).txt")).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(dir.join("a (2).txt")).unwrap(), "newer");
+    }
+
+    #[tokio::test]
+    async fn links_stop_at_their_file_limit() {
+        let (token, dir) = drop_into("drop_files", serde_json::json!({"max_files": 2})).await;
+        let response = upload(&token, &[("1", "a"), ("2", "b"), ("3", "c")]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(dir.join("1").exists() && dir.join("2").exists() && !dir.join("3").exists());
+        assert_eq!(upload(&token, &[("4", "d")]).await.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn links_stop_at_their_byte_limits() {
+        let (token, dir) = drop_into("drop_bytes", serde_json::json!({"max_file_bytes": 4})).await;
+        assert_eq!(upload(&token, &[("big", "12345")]).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.join("big").exists());
+
+        let (token, dir) = drop_into("drop_total", serde_json::json!({"max_total_bytes": 6})).await;
+        assert_eq!(upload(&token, &[("a", "123"), ("b", "456")]).await.status(), StatusCode::OK);
+        assert_eq!(upload(&token, &[("c", "7")]).await.status(), StatusCode::GONE);
+        assert!(!dir.join("c").exists());
+    }
+
+    #[tokio::test]
+    async fn reservations_hold_their_share_until_dropped() {
+        let (token, _) = drop_into("drop_reserve", serde_json::json!({"max_files": 2, "max_total_bytes": 100})).await;
+        let first = Reservation::take(&token, 60).unwrap();
+        assert_eq!(first.bytes, Some(60));
+        let second = Reservation::take(&token, u64::MAX).unwrap();
+        assert_eq!(second.bytes, Some(40));
+        assert_eq!(Reservation::take(&token, 1).err().unwrap().0, StatusCode::FORBIDDEN);
+
+        drop(second);
+        first.settle(10);
+        let third = Reservation::take(&token, u64::MAX).unwrap();
+        assert_eq!(third.bytes, Some(90));
+    }
+}
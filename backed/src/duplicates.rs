@@ -0,0 +1,477 @@
+// Finds files with the same content. Files are grouped by size, groups are
+// split by a hash of the first bytes of each file, and only files still
+// sharing a group are read in full. Searches run as background jobs like
+// copies do. Copies found can then be deleted or turned into hard links to
+// the file that is kept.
+use axum::{
+    extract::{Json, Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+use walkdir::WalkDir;
+
+use crate::{
+    acl::{self, Permission},
+    auth, config,
+    content_index::ContentIndex,
+    disk_usage, fs_ops, quota, resolve_path,
+    sandbox::{self, resolve_writable},
+    trash,
+};
+
+// Finished jobs stay around this long so clients can read the outcome
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+// Bytes hashed to split files of the same size before reading them in full
+const PARTIAL_BYTES: u64 = 64 * 1024;
+const HASH_BUFFER: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct FindReq {
+    path: Option<String>,
+    volume: Option<String>,
+    // Smaller files are ignored; empty ones by default
+    #[serde(default = "default_min_size")]
+    min_size: u64,
+}
+
+fn default_min_size() -> u64 {
+    1
+}
+
+#[derive(Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Scanning,
+    Hashing,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+// Files with the same size and content, sorted by path
+struct Group {
+    size: u64,
+    hash: String,
+    files: Vec<PathBuf>,
+}
+
+struct FindProgress {
+    state: JobState,
+    files_scanned: u64,
+    files_hashed: u64,
+    bytes_hashed: u64,
+    error: Option<String>,
+    // Largest waste first
+    groups: Vec<Group>,
+    finished_at: Option<SystemTime>,
+}
+
+struct FindJob {
+    id: String,
+    // Who started it; `None` with auth turned off
+    owner: Option<String>,
+    volume: String,
+    progress: Mutex<FindProgress>,
+    cancel: AtomicBool,
+}
+
+impl FindJob {
+    fn update(&self, f: impl FnOnce(&mut FindProgress)) {
+        f(&mut self.progress.lock().unwrap());
+    }
+
+    fn check_cancelled(&self) -> io::Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(fs_ops::cancelled());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct DuplicateGroup {
+    size: u64,
+    hash: String,
+    files: Vec<String>,
+    // Space freed by keeping a single file
+    wasted_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct FindStatus {
+    id: String,
+    // Volume the paths below belong to
+    volume: String,
+    state: JobState,
+    files_scanned: u64,
+    files_hashed: u64,
+    bytes_hashed: u64,
+    error: Option<String>,
+    groups: Vec<DuplicateGroup>,
+    wasted_bytes: u64,
+}
+
+impl FindJob {
+    fn status(&self) -> FindStatus {
+        let progress = self.progress.lock().unwrap();
+        let groups: Vec<DuplicateGroup> = progress
+            .groups
+            .iter()
+            .map(|g| DuplicateGroup {
+                size: g.size,
+                hash: g.hash.clone(),
+                files: g.files.iter().map(|f| sandbox::relative_path(f)).collect(),
+                wasted_bytes: wasted(g),
+            })
+            .collect();
+        FindStatus {
+            id: self.id.clone(),
+            volume: self.volume.clone(),
+            state: progress.state,
+            files_scanned: progress.files_scanned,
+            files_hashed: progress.files_hashed,
+            bytes_hashed: progress.bytes_hashed,
+            error: progress.error.clone(),
+            wasted_bytes: groups.iter().map(|g| g.wasted_bytes).sum(),
+            groups,
+        }
+    }
+}
+
+fn wasted(group: &Group) -> u64 {
+    group.size * (group.files.len() as u64 - 1)
+}
+
+#[derive(Clone)]
+struct FindState {
+    jobs: Arc<Mutex<HashMap<String, Arc<FindJob>>>>,
+    index: ContentIndex,
+}
+
+pub fn router(index: ContentIndex) -> Router {
+    Router::new()
+        .route("/", post(start_find))
+        .route("/:id", get(find_status).delete(cancel_find))
+        .route("/:id/resolve", post(resolve))
+        .with_state(FindState { jobs: Default::default(), index })
+}
+
+async fn start_find(State(state): State<FindState>, Json(payload): Json<FindReq>) -> impl IntoResponse {
+    let root = match resolve_path(payload.volume.as_deref(), payload.path) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&root, Permission::Read) {
+        return e.into_response();
+    }
+    if !root.is_dir() {
+        return (StatusCode::NOT_FOUND, "Folder not found").into_response();
+    }
+    let Some(volume) = sandbox::volume_of(&root) else {
+        return (StatusCode::NOT_FOUND, "No such volume").into_response();
+    };
+
+    let job = Arc::new(FindJob {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        owner: auth::current_user().map(|u| u.username),
+        volume: volume.name.clone(),
+        progress: Mutex::new(FindProgress {
+            state: JobState::Scanning,
+            files_scanned: 0,
+            files_hashed: 0,
+            bytes_hashed: 0,
+            error: None,
+            groups: Vec::new(),
+            finished_at: None,
+        }),
+        cancel: AtomicBool::new(false),
+    });
+
+    {
+        let mut jobs = state.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            let finished_at = job.progress.lock().unwrap().finished_at;
+            finished_at.is_none_or(|t| t.elapsed().unwrap_or_default() < FINISHED_JOB_TTL)
+        });
+        jobs.insert(job.id.clone(), job.clone());
+    }
+
+    let user = auth::current_user();
+    let min_size = payload.min_size;
+    let worker = job.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = auth::with_user(user, || find(&worker, &root, min_size));
+        worker.update(|p| {
+            p.finished_at = Some(SystemTime::now());
+            p.state = match result {
+                Ok(groups) => {
+                    p.groups = groups;
+                    JobState::Completed
+                }
+                Err(e) if fs_ops::is_cancelled(&e) => JobState::Cancelled,
+                Err(e) => {
+                    p.error = Some(e.to_string());
+                    JobState::Failed
+                }
+            };
+        });
+    });
+
+    (StatusCode::ACCEPTED, AxumJson(job.status())).into_response()
+}
+
+async fn find_status(State(state): State<FindState>, UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    let job = state.jobs.lock().unwrap().get(&id).cloned();
+    let Some(job) = job.filter(|job| auth::owns_job(job.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Job not found").into_response();
+    };
+    AxumJson(job.status()).into_response()
+}
+
+async fn cancel_find(State(state): State<FindState>, UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    let job = state.jobs.lock().unwrap().get(&id).cloned();
+    let Some(job) = job.filter(|job| auth::owns_job(job.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Job not found").into_response();
+    };
+    job.cancel.store(true, Ordering::Relaxed);
+    (StatusCode::ACCEPTED, "Cancelling").into_response()
+}
+
+fn find(job: &FindJob, root: &Path, min_size: u64) -> io::Result<Vec<Group>> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    // Hard links to the same data aren't copies of it
+    let mut seen_links = HashSet::new();
+
+    for entry in WalkDir::new(root).into_iter().filter_entry(|e| !sandbox::is_reserved(e.path())) {
+        job.check_cancelled()?;
+        let Ok(entry) = entry else { continue };
+        if !entry.file_type().is_file() || !acl::allows(entry.path(), Permission::Read) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else { continue };
+        if metadata.len() < min_size {
+            continue;
+        }
+        if let Some(id) = fs_ops::link_id(&metadata)
+            && !seen_links.insert(id)
+        {
+            continue;
+        }
+        by_size.entry(metadata.len()).or_default().push(entry.into_path());
+        job.update(|p| p.files_scanned += 1);
+    }
+
+    job.update(|p| p.state = JobState::Hashing);
+    let progress = |bytes: u64| {
+        job.check_cancelled()?;
+        job.update(|p| p.bytes_hashed += bytes);
+        Ok(())
+    };
+    let mut groups = Vec::new();
+    for (size, files) in by_size.into_iter().filter(|(_, files)| files.len() > 1) {
+        for (partial, files) in split_by_hash(job, files, Some(PARTIAL_BYTES), &progress)? {
+            // The partial hash already covered all of a small file
+            let split = if size <= PARTIAL_BYTES {
+                HashMap::from([(partial, files)])
+            } else {
+                split_by_hash(job, files, None, &progress)?
+            };
+            groups.extend(split.into_iter().map(|(hash, mut files)| {
+                files.sort();
+                Group { size, hash, files }
+            }));
+        }
+    }
+    groups.sort_by(|a, b| wasted(b).cmp(&wasted(a)).then_with(|| a.files.cmp(&b.files)));
+    Ok(groups)
+}
+
+// Hashes up to `limit` bytes of each file, keeping only hashes more than one
+// file shares. Files that can't be read are left out.
+fn split_by_hash(
+    job: &FindJob,
+    files: Vec<PathBuf>,
+    limit: Option<u64>,
+    progress: &dyn Fn(u64) -> io::Result<()>,
+) -> io::Result<HashMap<String, Vec<PathBuf>>> {
+    let mut by_hash: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for path in files {
+        match hash_file(&path, limit, progress) {
+            Ok(hash) => by_hash.entry(hash).or_default().push(path),
+            Err(e) if fs_ops::is_cancelled(&e) => return Err(e),
+            Err(_) => continue,
+        }
+        job.update(|p| p.files_hashed += 1);
+    }
+    by_hash.retain(|_, files| files.len() > 1);
+    Ok(by_hash)
+}
+
+fn hash_file(path: &Path, limit: Option<u64>, progress: &dyn Fn(u64) -> io::Result<()>) -> io::Result<String> {
+    let mut reader = File::open(path)?.take(limit.unwrap_or(u64::MAX));
+    let mut state = blake2b_simd::Params::new().hash_length(32).to_state();
+    let mut buf = vec![0; HASH_BUFFER];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        state.update(&buf[..read]);
+        progress(read as u64)?;
+    }
+    Ok(state.finalize().to_hex().to_string())
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Delete,
+    // Replace each file with a hard link to the copy that is kept
+    Hardlink,
+}
+
+#[derive(Deserialize)]
+struct ResolveReq {
+    action: Action,
+    // Files to get rid of, as listed in the job's groups
+    paths: Vec<String>,
+    // Delete without going through the trash
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[derive(Serialize)]
+struct Outcome {
+    path: String,
+    // Absent when the file was dealt with
+    error: Option<String>,
+}
+
+// Every file is read again first, and left alone unless it and the file kept
+// in its place still have the content the search found
+async fn resolve(
+    State(state): State<FindState>,
+    UrlPath(id): UrlPath<String>,
+    Json(payload): Json<ResolveReq>,
+) -> impl IntoResponse {
+    let job = state.jobs.lock().unwrap().get(&id).cloned();
+    let Some(job) = job.filter(|job| auth::owns_job(job.owner.as_deref())) else {
+        return (StatusCode::NOT_FOUND, "Job not found").into_response();
+    };
+
+    // Each selected file with the file kept in its group and the group's hash
+    let mut work = Vec::new();
+    {
+        let progress = job.progress.lock().unwrap();
+        if progress.state != JobState::Completed {
+            return (StatusCode::CONFLICT, "The search hasn't finished").into_response();
+        }
+        let mut selected = HashSet::new();
+        for relative in &payload.paths {
+            let path = match resolve_writable(Some(&job.volume), Some(relative.clone())) {
+                Ok(p) => p,
+                Err(e) => return e.into_response(),
+            };
+            if progress.groups.iter().all(|g| !g.files.contains(&path)) {
+                return (StatusCode::BAD_REQUEST, format!("{} is not a duplicate found by this search", relative))
+                    .into_response();
+            }
+            selected.insert(path);
+        }
+        for group in &progress.groups {
+            let Some(keep) = group.files.iter().find(|f| !selected.contains(*f)) else {
+                return (StatusCode::BAD_REQUEST, "Keep at least one file of each group").into_response();
+            };
+            for file in group.files.iter().filter(|f| selected.contains(*f)) {
+                work.push((file.clone(), keep.clone(), group.hash.clone(), group.size));
+            }
+        }
+    }
+
+    for (file, keep, _, _) in &work {
+        let permission = match payload.action {
+            Action::Delete => Permission::Delete,
+            Action::Hardlink => Permission::Write,
+        };
+        if let Err(e) = acl::check(file, permission).and_then(|_| acl::check(keep, Permission::Read)) {
+            return e.into_response();
+        }
+    }
+
+    let trash = !payload.permanent && config::get().features.trash;
+    let mut outcomes = Vec::new();
+    let mut done = HashSet::new();
+    for (file, keep, hash, size) in work {
+        let checked = (file.clone(), keep.clone());
+        let unchanged = tokio::task::spawn_blocking(move || {
+            let no_progress = |_: u64| Ok(());
+            [checked.0, checked.1].iter().all(|f| {
+                f.metadata().is_ok_and(|m| m.len() == size)
+                    && hash_file(f, None, &no_progress).is_ok_and(|h| h == hash)
+            })
+        })
+        .await
+        .unwrap();
+
+        let result = if !unchanged {
+            Err("Changed since the search".to_string())
+        } else {
+            match payload.action {
+                Action::Delete if trash => trash::move_to_trash(&file).await.map(|_| ()),
+                Action::Delete => fs::remove_file(&file).await,
+                Action::Hardlink => {
+                    let (link, target) = (file.clone(), keep.clone());
+                    tokio::task::spawn_blocking(move || replace_with_link(&target, &link)).await.unwrap()
+                }
+            }
+            .map_err(|e| e.to_string())
+        };
+
+        if result.is_ok() {
+            disk_usage::invalidate(&file);
+            if payload.action == Action::Delete {
+                // Trashed files stay charged to the owner until purged
+                if !trash {
+                    quota::record(&file, -(size as i64));
+                }
+                state.index.remove(&file);
+            }
+            done.insert(file.clone());
+        }
+        outcomes.push(Outcome { path: sandbox::relative_path(&file), error: result.err() });
+    }
+
+    // Dealt with files are no longer copies of anything
+    job.update(|p| {
+        for group in &mut p.groups {
+            group.files.retain(|f| !done.contains(f));
+        }
+        p.groups.retain(|g| g.files.len() > 1);
+    });
+    AxumJson(outcomes).into_response()
+}
+
+// Links `target` under a temporary name next to `path` first, so `path` is
+// replaced in one rename and never missing
+fn replace_with_link(target: &Path, path: &Path) -> io::Result<()> {
+    let staging = fs_ops::staging_path(path);
+    std::fs::hard_link(target, &staging)?;
+    std::fs::rename(&staging, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&staging);
+    })
+}
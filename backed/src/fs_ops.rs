@@ -0,0 +1,284 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+#[derive(Debug)]
+pub enum MoveError {
+    NotFound,
+    AlreadyExists,
+    // Moving a folder into itself or one of its descendants
+    IntoItself,
+    Io(io::Error),
+}
+
+impl From<io::Error> for MoveError {
+    fn from(e: io::Error) -> Self {
+        MoveError::Io(e)
+    }
+}
+
+impl std::fmt::Display for MoveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveError::NotFound => write!(f, "Source not found"),
+            MoveError::AlreadyExists => write!(f, "Destination already exists"),
+            MoveError::IntoItself => write!(f, "Cannot move a folder into itself"),
+            MoveError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Renames `from` to `to`, replacing an existing destination only when
+// `overwrite` is set. When the two sit on different filesystems the tree is
+// copied next to the destination and the source removed afterwards.
+pub fn move_path(from: &Path, to: &Path, overwrite: bool) -> Result<(), MoveError> {
+    let source = std::fs::symlink_metadata(from).map_err(|_| MoveError::NotFound)?;
+    if source.is_dir() && to.starts_with(from) {
+        return Err(MoveError::IntoItself);
+    }
+
+    if let Ok(existing) = std::fs::symlink_metadata(to) {
+        if !overwrite {
+            return Err(MoveError::AlreadyExists);
+        }
+        if same_file(from, to) {
+            return Ok(());
+        }
+        // `rename` replaces a file with a file atomically on its own, but
+        // can't put anything over a folder or a folder over a file
+        if existing.is_dir() || source.is_dir() {
+            remove_path(to)?;
+        }
+    }
+
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            // Copy under a temporary name so the destination never appears half-written
+            let staging = staging_path(to);
+            if let Err(e) = copy_recursive(from, &staging) {
+                let _ = remove_path(&staging);
+                return Err(e.into());
+            }
+            std::fs::rename(&staging, to)?;
+            remove_path(from)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Moves a single file into place, replacing a file but never a folder
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if std::fs::symlink_metadata(to).is_ok_and(|m| m.is_dir()) {
+        return Err(io::Error::new(io::ErrorKind::IsADirectory, "A folder with that name already exists"));
+    }
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let staging = staging_path(to);
+            let copied = std::fs::copy(from, &staging).and_then(|_| std::fs::rename(&staging, to));
+            if let Err(e) = copied {
+                let _ = std::fs::remove_file(&staging);
+                return Err(e);
+            }
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+// Copies links as links, the way `rename` keeps them, so a move never pulls
+// in what a link points to
+pub fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from).follow_root_links(false) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(from).unwrap();
+        let target = if relative.as_os_str().is_empty() { to.to_path_buf() } else { to.join(relative) };
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            copy_link(entry.path(), &target)?;
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), &target)?;
+        } else {
+            return Err(io::Error::other(format!("Can't copy special file {}", entry.path().display())));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_link(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_link(from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::other(format!("Can't copy link {}", from.display())))
+}
+
+pub fn remove_path(path: &Path) -> io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+// Bytes taken by the files in a tree, or by a single file
+pub fn tree_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Background jobs stop with this error once cancelled. It has a type of its
+// own so an `Interrupted` from the filesystem still counts as a failure.
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+pub fn cancelled() -> io::Error {
+    io::Error::other(Cancelled)
+}
+
+pub fn is_cancelled(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+// Whether a client supplied id has the form of the ids things are stored
+// under on disk, such as trashed items and uploads: a uuid as 32 hex digits.
+// Anything else could lead out of the folder it is looked up in.
+pub fn is_stored_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// A hidden name next to `to` to write under before renaming into place
+pub fn staging_path(to: &Path) -> PathBuf {
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    to.with_file_name(format!(".{}.{}.part", name, uuid::Uuid::new_v4().simple()))
+}
+
+// Reads one of the JSON files the server keeps its records in. A file that
+// doesn't exist yet reads as empty.
+pub fn read_json_or_default<T: DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes the whole file next to the old one, then swaps it in
+pub fn write_json_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let staging = path.with_extension("json.tmp");
+    std::fs::write(&staging, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&staging, path)
+}
+
+// Device and inode of a file with more than one name, to tell hard links
+// to the same data apart from copies
+#[cfg(unix)]
+pub fn link_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn link_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("disk_manager_fs_ops_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn moves_only_overwrite_when_asked() {
+        let dir = scratch("overwrite");
+        std::fs::write(dir.join("a"), "new").unwrap();
+        std::fs::write(dir.join("b"), "old").unwrap();
+        assert!(matches!(move_path(&dir.join("a"), &dir.join("b"), false), Err(MoveError::AlreadyExists)));
+        assert_eq!(std::fs::read_to_string(dir.join("b")).unwrap(), "old");
+
+        move_path(&dir.join("a"), &dir.join("b"), true).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("b")).unwrap(), "new");
+        assert!(!dir.join("a").exists());
+    }
+
+    #[test]
+    fn overwriting_moves_replace_folders_and_files_alike() {
+        let dir = scratch("replace");
+        std::fs::create_dir_all(dir.join("folder/inner")).unwrap();
+        std::fs::write(dir.join("file"), "file").unwrap();
+        move_path(&dir.join("file"), &dir.join("folder"), true).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("folder")).unwrap(), "file");
+
+        std::fs::create_dir_all(dir.join("other/inner")).unwrap();
+        move_path(&dir.join("other"), &dir.join("folder"), true).unwrap();
+        assert!(dir.join("folder/inner").is_dir());
+    }
+
+    #[test]
+    fn folders_cant_move_into_themselves() {
+        let dir = scratch("into_itself");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        assert!(matches!(move_path(&dir.join("a"), &dir.join("a/b/c"), true), Err(MoveError::IntoItself)));
+        assert!(matches!(move_path(&dir.join("missing"), &dir.join("x"), true), Err(MoveError::NotFound)));
+    }
+
+    #[test]
+    fn only_cancellation_reads_as_cancelled() {
+        assert!(is_cancelled(&cancelled()));
+        assert!(!is_cancelled(&io::Error::new(io::ErrorKind::Interrupted, "cancelled")));
+        assert!(!is_cancelled(&io::Error::other("cancelled")));
+    }
+
+    #[test]
+    fn file_moves_leave_folders_alone() {
+        let dir = scratch("move_file");
+        std::fs::create_dir_all(dir.join("folder")).unwrap();
+        std::fs::write(dir.join("folder/keep"), "keep").unwrap();
+        std::fs::write(dir.join("file"), "file").unwrap();
+        let error = move_file(&dir.join("file"), &dir.join("folder")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::IsADirectory);
+        assert_eq!(std::fs::read_to_string(dir.join("folder/keep")).unwrap(), "keep");
+
+        std::fs::write(dir.join("target"), "old").unwrap();
+        move_file(&dir.join("file"), &dir.join("target")).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("target")).unwrap(), "file");
+    }
+}
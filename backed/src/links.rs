@@ -0,0 +1,23 @@
+// What share and drop links have in common. Both are kept in memory and in a
+// JSON file that is rewritten whenever a link is added or used.
+use serde::Serialize;
+use std::{io, path::Path};
+
+use crate::fs_ops;
+
+pub trait Link: Serialize {
+    // Expired or used up, so it can be forgotten
+    fn closed(&self) -> bool;
+}
+
+// Adds `link` and writes `links` to `file`. Links that can no longer be used
+// are dropped on the way; the new one is taken out again if the write fails.
+pub fn add<T: Link>(links: &mut Vec<T>, link: T, file: &Path) -> io::Result<()> {
+    links.retain(|l| !l.closed());
+    links.push(link);
+    let result = fs_ops::write_json_atomic(file, links);
+    if result.is_err() {
+        links.pop();
+    }
+    result
+}
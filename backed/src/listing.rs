@@ -0,0 +1,425 @@
+use axum::http::{HeaderName, StatusCode};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    fs::Metadata,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::sandbox;
+
+#[derive(Serialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // Seconds since the unix epoch; not every platform or filesystem records all three
+    pub created: Option<u64>,
+    pub modified: Option<u64>,
+    pub accessed: Option<u64>,
+    pub is_symlink: bool,
+    // Only for links that stay inside the caller's root
+    pub symlink_target: Option<String>,
+    // Unix permission bits such as 0o644, absent on other platforms
+    pub mode: Option<u32>,
+    pub readonly: bool,
+    pub mime_type: String,
+}
+
+impl FileEntry {
+    // Describes `path`, looking through symlinks for everything but the link
+    // itself. Dangling links and links leading outside the caller's root are
+    // described by the link's own metadata, so nothing about what lies
+    // outside is given away.
+    pub async fn read(name: String, path: &Path) -> std::io::Result<Self> {
+        let link_metadata = fs::symlink_metadata(path).await?;
+        let is_symlink = link_metadata.file_type().is_symlink();
+        let inside = is_symlink && sandbox::link_stays_inside(path);
+        let symlink_target = if inside {
+            fs::read_link(path).await.ok().map(|t| t.to_string_lossy().to_string())
+        } else {
+            None
+        };
+        let metadata = if inside {
+            fs::metadata(path).await.unwrap_or(link_metadata)
+        } else {
+            link_metadata
+        };
+
+        let is_dir = metadata.is_dir();
+        let mime_type = if is_dir {
+            "inode/directory".to_string()
+        } else if is_symlink && !inside {
+            "inode/symlink".to_string()
+        } else {
+            detect_mime(path).await
+        };
+
+        Ok(FileEntry {
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { metadata.len() },
+            created: unix_secs(metadata.created()),
+            modified: unix_secs(metadata.modified()),
+            accessed: unix_secs(metadata.accessed()),
+            is_symlink,
+            symlink_target,
+            mode: mode(&metadata),
+            readonly: metadata.permissions().readonly(),
+            mime_type,
+        })
+    }
+}
+
+fn unix_secs(time: std::io::Result<SystemTime>) -> Option<u64> {
+    time.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+// Goes by extension first, and only opens the file to look at its leading
+// bytes when the extension says nothing
+pub async fn detect_mime(path: &Path) -> String {
+    if let Some(mime) = mime_guess::from_path(path).first() {
+        return mime.essence_str().to_string();
+    }
+
+    let mut head = [0u8; 16];
+    let read = match fs::File::open(path).await {
+        Ok(mut f) => f.read(&mut head).await.unwrap_or(0),
+        Err(_) => 0,
+    };
+    sniff(&head[..read]).unwrap_or("application/octet-stream").to_string()
+}
+
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF8", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+    ];
+
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(mime);
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return Some("video/mp4");
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    // A multi-byte character cut off at the end of the sample still counts as text
+    let is_text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if !head.is_empty() && is_text && !head.contains(&0) {
+        return Some("text/plain");
+    }
+    None
+}
+
+// Paging details travel in headers so the body stays a plain array of entries
+pub const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+pub const NEXT_CURSOR: HeaderName = HeaderName::from_static("x-next-cursor");
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+    // File extension
+    Type,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    pub path: Option<String>,
+    pub volume: Option<String>,
+    #[serde(default)]
+    pub sort: SortBy,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[serde(default = "default_true")]
+    pub dirs_first: bool,
+    // Name pattern such as `*.mp4` or `IMG_2024*`, matched case-insensitively
+    pub glob: Option<String>,
+    // Comma separated extensions, e.g. `jpg,png`; folders never match
+    pub ext: Option<String>,
+    // Include dot files, as listings always have; `false` leaves them out
+    #[serde(default = "default_true")]
+    pub hidden: bool,
+    pub limit: Option<usize>,
+    // Opaque value from a previous page's `X-Next-Cursor`
+    pub cursor: Option<String>,
+    // Add `X-Capacity-*` headers describing the filesystem the folder is on
+    #[serde(default)]
+    pub capacity: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub struct Page {
+    pub entries: Vec<FileEntry>,
+    // Matching entries across all pages
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
+// Name based filters, checked before any metadata is read
+pub struct NameFilter {
+    glob: Option<GlobMatcher>,
+    extensions: Option<Vec<String>>,
+    hidden: bool,
+}
+
+impl NameFilter {
+    pub fn new(query: &ListQuery) -> Result<Self, (StatusCode, String)> {
+        let glob = match &query.glob {
+            Some(pattern) => Some(
+                GlobBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid glob: {}", e)))?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+        let extensions = query.ext.as_ref().map(|list| {
+            list.split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect()
+        });
+        Ok(NameFilter { glob, extensions, hidden: query.hidden })
+    }
+
+    pub fn matches(&self, name: &str, is_dir: bool) -> bool {
+        if !self.hidden && name.starts_with('.') {
+            return false;
+        }
+        if let Some(glob) = &self.glob
+            && !glob.is_match(name)
+        {
+            return false;
+        }
+        if let Some(extensions) = &self.extensions {
+            return !is_dir && extensions.contains(&extension(name));
+        }
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+enum SortValue {
+    Number(u64),
+    Text(String),
+}
+
+// Position of an entry in the sorted listing. The cursor carries the key of
+// the last entry served, so pages stay consistent while entries come and go.
+#[derive(Serialize, Deserialize)]
+struct SortKey {
+    #[serde(rename = "d")]
+    is_dir: bool,
+    #[serde(rename = "k")]
+    value: SortValue,
+    #[serde(rename = "n")]
+    name: String,
+}
+
+impl SortKey {
+    fn of(entry: &FileEntry, sort: SortBy) -> Self {
+        let value = match sort {
+            SortBy::Name => SortValue::Text(entry.name.to_lowercase()),
+            SortBy::Size => SortValue::Number(entry.size),
+            SortBy::Mtime => SortValue::Number(entry.modified.unwrap_or(0)),
+            SortBy::Type => SortValue::Text(extension(&entry.name)),
+        };
+        SortKey { is_dir: entry.is_dir, value, name: entry.name.clone() }
+    }
+
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+    }
+}
+
+fn compare(a: &SortKey, b: &SortKey, query: &ListQuery) -> Ordering {
+    // Folders stay on top whichever direction the rest is sorted in
+    let folders = if query.dirs_first { b.is_dir.cmp(&a.is_dir) } else { Ordering::Equal };
+    folders.then_with(|| {
+        let ordering = a.value.cmp(&b.value).then_with(|| a.name.cmp(&b.name));
+        match query.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    })
+}
+
+pub fn paginate(entries: Vec<FileEntry>, query: &ListQuery) -> Result<Page, (StatusCode, String)> {
+    let after = match query.cursor.as_deref().filter(|c| !c.is_empty()) {
+        Some(cursor) => Some(SortKey::decode(cursor).ok_or((StatusCode::BAD_REQUEST, "Invalid cursor".to_string()))?),
+        None => None,
+    };
+
+    let mut keyed: Vec<(SortKey, FileEntry)> = entries
+        .into_iter()
+        .map(|e| (SortKey::of(&e, query.sort), e))
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| compare(a, b, query));
+
+    let total = keyed.len();
+    let start = match &after {
+        Some(after) => keyed.partition_point(|(key, _)| compare(key, after, query) != Ordering::Greater),
+        None => 0,
+    };
+    let end = match query.limit {
+        Some(limit) => start.saturating_add(limit).min(total),
+        None => total,
+    };
+
+    let next_cursor = if end < total && end > start {
+        Some(keyed[end - 1].0.encode())
+    } else {
+        None
+    };
+    let entries = keyed.drain(start..end).map(|(_, e)| e).collect();
+
+    Ok(Page { entries, total, next_cursor })
+}
+
+fn extension(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(json: serde_json::Value) -> ListQuery {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn entry(name: &str, is_dir: bool, size: u64) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            created: None,
+            modified: None,
+            accessed: None,
+            is_symlink: false,
+            symlink_target: None,
+            mode: None,
+            readonly: false,
+            mime_type: String::new(),
+        }
+    }
+
+    fn entries() -> Vec<FileEntry> {
+        vec![entry("b.txt", false, 3), entry("A.jpg", false, 1), entry("zeta", true, 0), entry("c.PNG", false, 2)]
+    }
+
+    fn names(page: &Page) -> Vec<&str> {
+        page.entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn folders_come_first_then_names_ignoring_case() {
+        let page = paginate(entries(), &query(serde_json::json!({}))).unwrap();
+        assert_eq!(names(&page), ["zeta", "A.jpg", "b.txt", "c.PNG"]);
+        assert_eq!(page.total, 4);
+        assert!(page.next_cursor.is_none());
+
+        let page = paginate(entries(), &query(serde_json::json!({"sort": "size", "order": "desc"}))).unwrap();
+        assert_eq!(names(&page), ["zeta", "b.txt", "c.PNG", "A.jpg"]);
+    }
+
+    #[test]
+    fn cursors_continue_after_the_last_entry_served() {
+        let first = paginate(entries(), &query(serde_json::json!({"limit": 2}))).unwrap();
+        assert_eq!(names(&first), ["zeta", "A.jpg"]);
+        let cursor = first.next_cursor.unwrap();
+
+        // An entry before the cursor disappearing doesn't shift the next page
+        let mut fewer = entries();
+        fewer.retain(|e| e.name != "A.jpg");
+        let second = paginate(fewer, &query(serde_json::json!({"limit": 2, "cursor": cursor}))).unwrap();
+        assert_eq!(names(&second), ["b.txt", "c.PNG"]);
+        assert_eq!(second.total, 3);
+        assert!(second.next_cursor.is_none());
+    }
+
+    #[test]
+    fn bad_cursors_are_a_bad_request() {
+        for cursor in ["not a cursor", "e30"] {
+            let result = paginate(entries(), &query(serde_json::json!({"cursor": cursor})));
+            assert_eq!(result.err().map(|(status, _)| status), Some(StatusCode::BAD_REQUEST), "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn bad_globs_are_a_bad_request() {
+        let result = NameFilter::new(&query(serde_json::json!({"glob": "[a-"})));
+        assert_eq!(result.err().map(|(status, _)| status), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn dot_files_are_listed_unless_left_out() {
+        assert!(NameFilter::new(&query(serde_json::json!({}))).unwrap().matches(".env", false));
+        assert!(!NameFilter::new(&query(serde_json::json!({"hidden": false}))).unwrap().matches(".env", false));
+    }
+
+    #[test]
+    fn names_are_filtered_by_glob_and_extension() {
+        let filter = NameFilter::new(&query(serde_json::json!({"glob": "img_*"}))).unwrap();
+        assert!(filter.matches("IMG_2024.jpg", false));
+        assert!(!filter.matches("photo.jpg", false));
+
+        let filter = NameFilter::new(&query(serde_json::json!({"ext": "jpg, .PNG"}))).unwrap();
+        assert!(filter.matches("a.JPG", false) && filter.matches("b.png", false));
+        assert!(!filter.matches("c.txt", false) && !filter.matches("d.jpg", true));
+    }
+}
@@ -1,79 +1,239 @@
 use axum::{
-    extract::{Multipart, Json, Query, DefaultBodyLimit},
-    http::{StatusCode, Method, header},
-    response::{IntoResponse, Json as AxumJson},
+    extract::{Multipart, Json, Query, DefaultBodyLimit, State},
+    http::{StatusCode, Method, HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Json as AxumJson, Response},
     body::Body,
     routing::{get, post},
     Router,
 };
-use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf};
-use tower_http::cors::{Any, CorsLayer};
-use tokio::fs;
+use serde::{Deserialize, Serialize};
+use std::{io::SeekFrom, path::Path};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tokio::{fs, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
 use tokio_util::io::ReaderStream;
 
+mod acl;
+mod auth;
+mod capacity;
+mod checksum;
+mod config;
+mod content_index;
+mod copy;
+mod disk_usage;
+mod duplicates;
+mod drops;
+mod fs_ops;
+mod links;
+mod listing;
+mod mounts;
+mod quota;
+mod range;
+mod sandbox;
+mod search;
+mod shares;
+mod trash;
+mod tus;
+mod upload;
+mod versions;
+mod zip_stream;
+
+use acl::Permission;
+use content_index::ContentIndex;
+use listing::{FileEntry, ListQuery};
+use range::RangeRequest;
+use sandbox::{resolve_path, resolve_writable};
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let config = match config::load() {
+        Ok((config, print)) if print => {
+            print!("{}", toml::to_string_pretty(&config).unwrap());
+            return;
+        }
+        Ok((config, _)) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for e in errors {
+                eprintln!("  {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+    tracing_subscriber::fmt().with_max_level(config.log_filter()).init();
+    config::init(config.clone());
 
-    let storage_path = PathBuf::from("storage");
-    if !storage_path.exists() {
-        fs::create_dir_all(&storage_path).await.unwrap();
+    for volume in config.volumes.iter().filter(|v| !v.read_only && !v.path.exists()) {
+        fs::create_dir_all(&volume.path).await.unwrap();
     }
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::DELETE])
-        .allow_headers(Any);
+    let index = ContentIndex::default();
+    if config.features.content_index {
+        index.build_in_background();
+    }
+    if config.features.versions {
+        versions::spawn_retention_purge();
+    }
+    if config.features.trash {
+        trash::spawn_retention_purge();
+    }
 
-    let app = Router::new()
+    let origins = if config.cors_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.cors_origins.iter().map(|o| HeaderValue::from_str(o).unwrap()))
+    };
+    let cors = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::HEAD, Method::PATCH, Method::OPTIONS])
+        .allow_headers(Any)
+        .expose_headers(
+            [
+                header::ACCEPT_RANGES,
+                header::CONTENT_RANGE,
+                header::CONTENT_LENGTH,
+                header::CONTENT_DISPOSITION,
+                header::ETAG,
+                header::LAST_MODIFIED,
+                listing::TOTAL_COUNT,
+                listing::NEXT_CURSOR,
+            ]
+            .into_iter()
+            .chain(tus::EXPOSED_HEADERS)
+            .chain(capacity::EXPOSED_HEADERS)
+            .collect::<Vec<_>>(),
+        );
+
+    let mut api = Router::new()
         .route("/", get(root))
+        .route("/volumes", get(list_volumes))
+        .route("/capacity", get(capacity::report))
+        .route("/usage", get(quota::usage_report))
         .route("/create_folder", post(create_folder))
-        .route("/upload", post(upload_file))
+        .route("/upload", post(upload_file).layer(upload::body_limit()))
         .route("/list", get(list_files))
         .route("/download", get(download_file))
+        .route("/checksum", get(checksum::checksum))
         .route("/delete", axum::routing::delete(delete_file))
-        .layer(DefaultBodyLimit::max(1024 * 1024 * 1024)) // 1GB limit
-        .layer(cors);
+        .route("/move", post(move_entry));
+    if config.features.search {
+        api = api.route("/search", get(search::search));
+    }
+    if config.features.content_index {
+        api = api.route("/content_search", get(content_index::content_search));
+    }
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    println!("listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let mut app = api
+        .with_state(index.clone())
+        .nest("/copy", copy::router(index.clone()))
+        .nest("/disk_usage", disk_usage::router())
+        .nest("/duplicates", duplicates::router(index.clone()))
+        .nest("/mounts", mounts::router(index.clone()));
+    if config.features.trash {
+        app = app.nest("/trash", trash::router(index.clone()));
+    }
+    if config.features.versions {
+        app = app.nest("/versions", versions::router(index.clone()));
+    }
+    if config.features.resumable_uploads {
+        app = app.nest("/uploads", tus::router(index.clone()));
+    }
+    if config.features.shares {
+        if let Err(e) = shares::load() {
+            eprintln!("Cannot read {}: {}", config.links.shares_file.display(), e);
+            std::process::exit(1);
+        }
+        app = app.nest("/shares", shares::router());
+    }
+    if config.features.drops {
+        if let Err(e) = drops::load() {
+            eprintln!("Cannot read {}: {}", config.links.drops_file.display(), e);
+            std::process::exit(1);
+        }
+        app = app.nest("/drops", drops::router());
+    }
+    let auth = config.features.auth.then(|| match auth::Auth::load() {
+        Ok(auth) => auth,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", config.auth.users_file.display(), e);
+            std::process::exit(1);
+        }
+    });
+    if let Some(auth) = auth.clone() {
+        if let Err(e) = acl::load() {
+            eprintln!("Cannot read {}: {}", config.auth.acl_file.display(), e);
+            std::process::exit(1);
+        }
+        app = app
+            .nest("/auth", auth::router(auth.clone()))
+            .nest("/acl", acl::router())
+            .route_layer(axum::middleware::from_fn_with_state(auth, auth::require_user));
+    }
+    // Added after the sign-in check so links work without an account
+    if config.features.shares {
+        app = app.nest("/s", shares::public_router());
+    }
+    if config.features.drops {
+        app = app.nest("/d", drops::public_router(index, auth));
+    }
+    app = app.layer(DefaultBodyLimit::max(config.max_body_bytes as usize)).layer(cors);
+    if config.features.resumable_uploads {
+        app = app.layer(axum::middleware::from_fn(tus::advertise_capabilities));
+    }
+
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in &config.listen {
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("Cannot listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        println!("listening on {}", addr);
+        servers.spawn(axum::serve(listener, app.clone()).into_future());
+    }
+    while let Some(result) = servers.join_next().await {
+        result.unwrap().unwrap();
+    }
 }
 
 async fn root() -> &'static str {
     "Disk Manager Backend Running"
 }
 
-// Helper to sanitize and resolve path
-fn resolve_path(subpath: Option<String>) -> Result<PathBuf, String> {
-    let base = PathBuf::from("storage");
-    let sub = subpath.unwrap_or_default();
-    
-    // Simple sanitization
-    if sub.contains("..") {
-        return Err("Invalid path".to_string());
-    }
-    
-    // Remove leading slashes to append correctly
-    let clean_sub = sub.trim_start_matches('/');
-    Ok(base.join(clean_sub))
+#[derive(Serialize)]
+struct VolumeInfo {
+    name: String,
+    read_only: bool,
+}
+
+// The first volume is the one used when a request names none
+async fn list_volumes() -> impl IntoResponse {
+    let volumes: Vec<VolumeInfo> = sandbox::volumes()
+        .into_iter()
+        .map(|v| VolumeInfo { name: v.name.clone(), read_only: v.read_only })
+        .collect();
+    AxumJson(volumes)
 }
 
 #[derive(Deserialize)]
 struct PathReq {
     path: String,
+    volume: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct OptionalPathReq {
     path: Option<String>,
+    volume: Option<String>,
 }
 
 async fn create_folder(Json(payload): Json<PathReq>) -> impl IntoResponse {
-    match resolve_path(Some(payload.path)) {
+    match resolve_writable(payload.volume.as_deref(), Some(payload.path)) {
         Ok(path) => {
+            if let Err(e) = acl::check(&path, Permission::Write) {
+                return e.into_response();
+            }
             if path.exists() {
                 return (StatusCode::CONFLICT, "Folder or file already exists").into_response();
             }
@@ -82,47 +242,100 @@ async fn create_folder(Json(payload): Json<PathReq>) -> impl IntoResponse {
                 Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
             }
         },
-        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
+// Boundaries and part headers around the files in an upload body
+const MULTIPART_OVERHEAD: u64 = 4096;
+
 async fn upload_file(
+    State(index): State<ContentIndex>,
     Query(params): Query<OptionalPathReq>,
+    headers: HeaderMap,
     mut multipart: Multipart
 ) -> impl IntoResponse {
-    let target_dir = match resolve_path(params.path) {
+    let target_dir = match resolve_writable(params.volume.as_deref(), params.path) {
         Ok(p) => p,
-        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => return e.into_response(),
     };
+    if let Err(e) = acl::check(&target_dir, Permission::Write) {
+        return e.into_response();
+    }
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let file_name = if let Some(name) = field.file_name() {
-            name.to_string()
-        } else {
-            continue;
+    let content_length: u64 = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let mut length_checked = false;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
         };
 
-        if file_name.is_empty() { continue; }
-
-        let data = match field.bytes().await {
-            Ok(d) => d,
-            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        let Some(file_name) = upload::field_file_name(&field) else {
+            continue;
         };
 
-        let path = target_dir.join(file_name);
-        
-        if let Err(e) = fs::write(path, data).await {
-             return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        let target = target_dir.join(file_name);
+        // A rule can sit on the file itself
+        if let Err(e) = acl::check(&target, Permission::Write) {
+            return e.into_response();
         }
+        // Replacing a file frees what it took up, unless it is kept as a version
+        let replaced = fs::metadata(&target).await.map(|m| m.len()).unwrap_or(0);
+        let freed = if config::get().features.versions { 0 } else { replaced };
+        let limit = quota::remaining().await.map(|left| left.saturating_add(freed));
+
+        // Turn away uploads that can't fit before reading any file content.
+        // The length includes multipart framing, so allow a little for that;
+        // the exact limit is enforced while the files are written.
+        if !length_checked {
+            length_checked = true;
+            if limit.is_some_and(|limit| content_length.saturating_sub(MULTIPART_OVERHEAD) > limit) {
+                return quota::exceeded().into_response();
+            }
+        }
+
+        let written = match upload::save_field(field, &target, limit).await {
+            Ok(written) => written,
+            Err(e) => return e.into_response(),
+        };
+        quota::record(&target, written as i64 - replaced as i64);
+        disk_usage::invalidate(&target);
+        index.refresh(target);
     }
 
     (StatusCode::OK, "File uploaded").into_response()
 }
 
-async fn list_files(Query(params): Query<OptionalPathReq>) -> impl IntoResponse {
-    let path = match resolve_path(params.path) {
+async fn list_files(Query(params): Query<ListQuery>) -> impl IntoResponse {
+    let path = match resolve_path(params.volume.as_deref(), params.path.clone()) {
         Ok(p) => p,
-        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&path, Permission::Read) {
+        return e.into_response();
+    }
+    let mut response = list_dir(&path, &params).await;
+    if params.capacity
+        && response.status().is_success()
+        && let Ok(capacity) = capacity::of(&path)
+    {
+        capacity.insert_headers(response.headers_mut());
+    }
+    response
+}
+
+// Lists the entries of `path` the caller can see, a page at a time
+pub async fn list_dir(path: &Path, params: &ListQuery) -> Response {
+    let filter = match listing::NameFilter::new(params) {
+        Ok(f) => f,
+        Err(e) => return e.into_response(),
     };
     
     let mut entries = Vec::new();
@@ -130,111 +343,269 @@ async fn list_files(Query(params): Query<OptionalPathReq>) -> impl IntoResponse
     if let Ok(mut read_dir) = fs::read_dir(path).await {
          while let Ok(Some(entry)) = read_dir.next_entry().await {
              let name = entry.file_name().to_string_lossy().to_string();
-             let is_dir = entry.file_type().await.map(|ft| ft.is_dir()).unwrap_or(false);
-             entries.push(FileEntry { name, is_dir });
+             let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+             // Entries the user can't read aren't shown at all
+             if !filter.matches(&name, is_dir)
+                 || sandbox::is_reserved(&entry.path())
+                 || !acl::allows(&entry.path(), Permission::Read)
+             {
+                 continue;
+             }
+             if let Ok(file_entry) = FileEntry::read(name, &entry.path()).await {
+                 entries.push(file_entry);
+             }
          }
     }
-    
-    AxumJson(entries).into_response()
+
+    let page = match listing::paginate(entries, params) {
+        Ok(page) => page,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut response = AxumJson(page.entries).into_response();
+    let headers = response.headers_mut();
+    headers.insert(listing::TOTAL_COUNT, page.total.into());
+    if let Some(cursor) = page.next_cursor.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        headers.insert(listing::NEXT_CURSOR, cursor);
+    }
+    response
 }
 
-async fn download_file(Query(params): Query<PathReq>) -> impl IntoResponse {
-    let path = match resolve_path(Some(params.path)) {
+async fn download_file(Query(params): Query<PathReq>, headers: HeaderMap) -> impl IntoResponse {
+    let path = match resolve_path(params.volume.as_deref(), Some(params.path)) {
         Ok(p) => p,
-        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => return e.into_response(),
     };
 
+    if let Err(e) = acl::check(&path, Permission::Read) {
+        return e.into_response();
+    }
+    send_path(&path, &headers).await
+}
+
+// Sends a file, honouring range requests, or a folder as a zip
+pub async fn send_path(path: &Path, headers: &HeaderMap) -> Response {
     if !path.exists() {
         return (StatusCode::NOT_FOUND, "Not found").into_response();
     }
 
     if path.is_file() {
-        let file = match fs::File::open(&path).await {
-            Ok(f) => f,
-            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Could not open file").into_response(),
+        return serve_file(path, headers).await;
+    }
+
+    // Is directory: stream it as a zip
+    let filename = format!("{}.zip", path.file_name().unwrap().to_string_lossy());
+    let headers = [
+        (header::CONTENT_TYPE, "application/zip"),
+        (header::CONTENT_DISPOSITION, &format!("attachment; filename=\"{}\"", filename)),
+    ];
+    (headers, zip_stream::zip_directory(path.to_path_buf())).into_response()
+}
+
+async fn serve_file(path: &Path, headers: &HeaderMap) -> Response {
+    let mut file = match fs::File::open(path).await {
+        Ok(f) => f,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Could not open file").into_response(),
+    };
+    let metadata = match file.metadata().await {
+        Ok(m) => m,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Could not read file metadata").into_response(),
+    };
+
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = range::etag(len, modified);
+    let filename = path.file_name().unwrap().to_string_lossy().to_string();
+
+    let mut builder = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename));
+    if let Some(modified) = modified {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    if let Some(etag) = &etag {
+        builder = builder.header(header::ETAG, etag);
+    }
+
+    if range::not_modified(headers, modified, etag.as_deref()) {
+        return match builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()) {
+            Ok(response) => response,
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
         };
+    }
 
-        let stream = ReaderStream::new(file);
-        let body = Body::from_stream(stream);
-
-        let filename = path.file_name().unwrap().to_string_lossy().to_string();
-        
-        let headers = [
-            (header::CONTENT_TYPE, "application/octet-stream"),
-            (header::CONTENT_DISPOSITION, &format!("attachment; filename=\"{}\"", filename)),
-        ];
-
-        return (headers, body).into_response();
-    }
-
-    // Is directory: Zip it
-    let path_clone = path.clone();
-    let zip_buffer = tokio::task::spawn_blocking(move || {
-        use std::io::Write;
-        use walkdir::WalkDir;
-        
-        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
-        let options = zip::write::SimpleFileOptions::default()
-            .compression_method(zip::CompressionMethod::Stored);
-
-        let parent_dir = path_clone.parent().unwrap_or(&path_clone);
-
-        for entry in WalkDir::new(&path_clone) {
-            let entry = entry.map_err(|e| e.to_string())?;
-            let path = entry.path();
-            
-            if path.is_file() {
-                let name = path.strip_prefix(parent_dir).unwrap().to_str().unwrap();
-                
-                zip.start_file(name, options).map_err(|e| e.to_string())?;
-                let mut f = std::fs::File::open(path).map_err(|e| e.to_string())?;
-                let mut content = Vec::new(); 
-                use std::io::Read;
-                f.read_to_end(&mut content).map_err(|e| e.to_string())?;
-                zip.write_all(&content).map_err(|e| e.to_string())?;
+    let result = match range::from_headers(headers, len, modified, etag.as_deref()) {
+        RangeRequest::Full => builder
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, len)
+            .body(Body::from_stream(ReaderStream::new(file))),
+        RangeRequest::Unsatisfiable => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty()),
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            if let Err(e) = file.seek(SeekFrom::Start(range.start)).await {
+                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
             }
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_RANGE, range.content_range(len))
+                .header(header::CONTENT_LENGTH, range.len())
+                .body(Body::from_stream(ReaderStream::new(file.take(range.len()))))
         }
-        let cursor = zip.finish().map_err(|e| e.to_string())?;
-        Ok::<Vec<u8>, String>(cursor.into_inner())
-    }).await.unwrap();
-
-    match zip_buffer {
-        Ok(buffer) => {
-            let filename = format!("{}.zip", path.file_name().unwrap().to_string_lossy());
-            let headers = [
-                (header::CONTENT_TYPE, "application/zip"),
-                (header::CONTENT_DISPOSITION, &format!("attachment; filename=\"{}\"", filename)),
-            ];
-             (headers, buffer).into_response()
-        },
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        RangeRequest::Partial(ranges) => {
+            let boundary = range::multipart_boundary();
+            let parts: Vec<_> = ranges
+                .into_iter()
+                .map(|r| {
+                    let head = format!(
+                        "\r\n--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: {}\r\n\r\n",
+                        boundary,
+                        r.content_range(len)
+                    );
+                    (r, head)
+                })
+                .collect();
+            let tail = format!("\r\n--{}--\r\n", boundary);
+            let content_length = parts.iter().map(|(r, head)| head.len() as u64 + r.len()).sum::<u64>()
+                + tail.len() as u64;
+
+            // Parts are written into a pipe as the client reads, so only one
+            // buffer's worth of the file is in memory at a time
+            let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(async move {
+                for (r, head) in parts {
+                    writer.write_all(head.as_bytes()).await?;
+                    file.seek(SeekFrom::Start(r.start)).await?;
+                    tokio::io::copy(&mut (&mut file).take(r.len()), &mut writer).await?;
+                }
+                writer.write_all(tail.as_bytes()).await?;
+                writer.shutdown().await
+            });
+
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary))
+                .header(header::CONTENT_LENGTH, content_length)
+                .body(Body::from_stream(ReaderStream::new(reader)))
+        }
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
 
-#[derive(serde::Serialize)]
-struct FileEntry {
-    name: String,
-    is_dir: bool,
+#[derive(Deserialize)]
+struct DeleteReq {
+    path: String,
+    volume: Option<String>,
+    // Skip the trash and delete right away
+    #[serde(default)]
+    permanent: bool,
 }
 
-async fn delete_file(Query(params): Query<PathReq>) -> impl IntoResponse {
-    let path = match resolve_path(Some(params.path)) {
+async fn delete_file(State(index): State<ContentIndex>, Query(params): Query<DeleteReq>) -> impl IntoResponse {
+    let path = match resolve_writable(params.volume.as_deref(), Some(params.path)) {
         Ok(p) => p,
-        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(e) => return e.into_response(),
     };
+    if sandbox::relative_path(&path).is_empty() {
+        return (StatusCode::BAD_REQUEST, "Cannot delete the volume root").into_response();
+    }
+    if let Err(e) = acl::check_tree(&path, Permission::Delete) {
+        return e.into_response();
+    }
 
     if !path.exists() {
         return (StatusCode::NOT_FOUND, "Not found").into_response();
     }
 
-    let result = if path.is_dir() {
-        fs::remove_dir_all(path).await
+    // Only homes are accounted for, so only they need measuring
+    let size = match quota::owner_of(&path) {
+        Some(_) => {
+            let measured = path.clone();
+            tokio::task::spawn_blocking(move || fs_ops::tree_size(&measured)).await.unwrap()
+        }
+        None => 0,
+    };
+
+    // Trashed entries stay charged to the owner until they are purged
+    let trashed = !params.permanent && config::get().features.trash;
+    let result = if trashed {
+        trash::move_to_trash(&path).await.map(|_| ())
+    } else if path.is_dir() {
+        fs::remove_dir_all(&path).await
     } else {
-        fs::remove_file(path).await
+        fs::remove_file(&path).await
     };
+    index.remove(&path);
+    disk_usage::invalidate(&path);
+    if result.is_ok() && !trashed {
+        quota::record(&path, -(size as i64));
+    }
 
     match result {
+        Ok(_) if trashed => (StatusCode::OK, "Moved to trash").into_response(),
         Ok(_) => (StatusCode::OK, "Deleted").into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
+
+#[derive(Deserialize)]
+struct MoveReq {
+    from: String,
+    to: String,
+    volume: Option<String>,
+    // Volume to move into, the source volume by default
+    to_volume: Option<String>,
+    // Replace whatever already lives at `to` instead of failing
+    #[serde(default)]
+    overwrite: bool,
+}
+
+async fn move_entry(State(index): State<ContentIndex>, Json(payload): Json<MoveReq>) -> impl IntoResponse {
+    let to_volume = payload.to_volume.as_deref().or(payload.volume.as_deref());
+    let (from, to) = match (
+        resolve_writable(payload.volume.as_deref(), Some(payload.from)),
+        resolve_writable(to_volume, Some(payload.to)),
+    ) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+    if sandbox::relative_path(&from).is_empty() || sandbox::relative_path(&to).is_empty() {
+        return (StatusCode::BAD_REQUEST, "Cannot move the volume root").into_response();
+    }
+    // Moving takes the entry away from where it was and writes it anew
+    if let Err(e) = acl::check_tree(&from, Permission::Delete).and_then(|_| acl::check_tree(&to, Permission::Write)) {
+        return e.into_response();
+    }
+
+    if !to.parent().is_some_and(|p| p.is_dir()) {
+        return (StatusCode::NOT_FOUND, "Destination folder not found").into_response();
+    }
+
+    let (moved_from, moved_to) = (from.clone(), to.clone());
+    let result = tokio::task::spawn_blocking(move || fs_ops::move_path(&from, &to, payload.overwrite))
+        .await
+        .unwrap();
+
+    match result {
+        Ok(()) => {
+            quota::invalidate(&moved_from);
+            quota::invalidate(&moved_to);
+            disk_usage::invalidate(&moved_from);
+            disk_usage::invalidate(&moved_to);
+            index.remove(&moved_from);
+            index.refresh(moved_to);
+            (StatusCode::OK, "Moved").into_response()
+        }
+        Err(e @ fs_ops::MoveError::NotFound) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        Err(e @ fs_ops::MoveError::AlreadyExists) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(e @ fs_ops::MoveError::IntoItself) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e @ fs_ops::MoveError::Io(_)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
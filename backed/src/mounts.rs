@@ -0,0 +1,243 @@
+// Filesystems mounted on the host, as the kernel lists them in
+// `/proc/self/mountinfo`. Admins can add one as a volume without a restart.
+use axum::{
+    extract::{Json, Path as UrlPath, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+    routing::{delete, get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{io, path::PathBuf};
+
+use crate::{
+    auth::CurrentUser,
+    capacity::{self, Capacity},
+    config::{self, Volume},
+    content_index::ContentIndex,
+    sandbox,
+};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+pub fn router(index: ContentIndex) -> Router {
+    Router::new()
+        .route("/", get(list_mounts))
+        .route("/volumes", post(add_volume))
+        .route("/volumes/:name", delete(remove_volume))
+        .with_state(index)
+}
+
+#[derive(Serialize)]
+struct Mount {
+    // `major:minor` of the device
+    device: String,
+    // What was mounted, e.g. `/dev/sda1` or `tmpfs`
+    source: String,
+    mount_point: PathBuf,
+    fs_type: String,
+    // Options of this mount point, then those of the filesystem itself
+    options: Vec<String>,
+    super_options: Vec<String>,
+    // Volume served from this mount point, if any
+    volume: Option<String>,
+    capacity: Option<Capacity>,
+}
+
+// Fields are separated by spaces, with a variable number of optional fields
+// ended by a lone `-`:
+// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+fn parse_line(line: &str) -> Option<Mount> {
+    let (mount_fields, fs_fields) = line.split_once(" - ")?;
+    let mut mount_fields = mount_fields.split(' ');
+    let device = mount_fields.nth(2)?;
+    let mount_point = mount_fields.nth(1)?;
+    let options = mount_fields.next()?;
+    let mut fs_fields = fs_fields.split(' ');
+    let fs_type = fs_fields.next()?;
+    let source = fs_fields.next()?;
+    let super_options = fs_fields.next().unwrap_or("");
+
+    let list = |options: &str| options.split(',').filter(|o| !o.is_empty()).map(str::to_string).collect();
+    Some(Mount {
+        device: device.to_string(),
+        source: unescape(source),
+        mount_point: PathBuf::from(unescape(mount_point)),
+        fs_type: unescape(fs_type),
+        options: list(options),
+        super_options: list(super_options),
+        volume: None,
+        capacity: None,
+    })
+}
+
+// Spaces, tabs, newlines and backslashes in paths are written as `\` and
+// three octal digits
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes.get(i + 1..i + 4).and_then(|d| std::str::from_utf8(d).ok());
+        match code.and_then(|d| u8::from_str_radix(d, 8).ok()) {
+            Some(c) if bytes[i] == b'\\' => {
+                out.push(c);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn read_mounts() -> io::Result<Vec<Mount>> {
+    let text = std::fs::read_to_string(MOUNTINFO)?;
+    let volumes = sandbox::volumes();
+    Ok(text
+        .lines()
+        .filter_map(parse_line)
+        .map(|mut mount| {
+            mount.volume = volumes.iter().find(|v| v.path == mount.mount_point).map(|v| v.name.clone());
+            mount.capacity = capacity::of(&mount.mount_point).ok();
+            mount
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct MountQuery {
+    // Include filesystems without any blocks, such as proc and cgroup, like `df -a`
+    #[serde(default)]
+    all: bool,
+}
+
+async fn list_mounts(Query(query): Query<MountQuery>) -> impl IntoResponse {
+    // Asking a hung network filesystem for its size can block for a while
+    let mounts = match tokio::task::spawn_blocking(read_mounts).await.unwrap() {
+        Ok(mounts) => mounts,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return (StatusCode::NOT_IMPLEMENTED, "Mounts can only be listed on Linux").into_response();
+        }
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let mounts: Vec<Mount> = mounts
+        .into_iter()
+        .filter(|m| query.all || m.capacity.as_ref().is_some_and(|c| c.total_bytes > 0))
+        .collect();
+    AxumJson(mounts).into_response()
+}
+
+// Exposing host folders is only allowed to signed-in admins, never to
+// everyone on a server running without accounts
+fn admins_only(current: Option<&CurrentUser>) -> Result<(), (StatusCode, String)> {
+    match current {
+        Some(user) if user.is_admin => Ok(()),
+        _ => Err((StatusCode::FORBIDDEN, "Admins only".to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+struct AddVolumeReq {
+    name: String,
+    mount_point: PathBuf,
+    #[serde(default)]
+    read_only: bool,
+}
+
+async fn add_volume(
+    State(index): State<ContentIndex>,
+    current: Option<CurrentUser>,
+    Json(payload): Json<AddVolumeReq>,
+) -> impl IntoResponse {
+    if let Err(e) = admins_only(current.as_ref()) {
+        return e.into_response();
+    }
+    if !config::valid_volume_name(&payload.name) {
+        return (StatusCode::BAD_REQUEST, "Volume names may only use letters, digits, '-' and '_'").into_response();
+    }
+    let mounted = match tokio::task::spawn_blocking(read_mounts).await.unwrap() {
+        Ok(mounts) => mounts.iter().any(|m| m.mount_point == payload.mount_point),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    if !mounted {
+        return (StatusCode::NOT_FOUND, "Nothing is mounted there").into_response();
+    }
+
+    let volume = Volume { name: payload.name, path: payload.mount_point, read_only: payload.read_only };
+    let Some(volume) = sandbox::add_volume(volume) else {
+        return (StatusCode::CONFLICT, "A volume with this name already exists").into_response();
+    };
+    tracing::info!("volume '{}' added at {}", volume.name, volume.path.display());
+    index.refresh(volume.path.clone());
+    (StatusCode::CREATED, format!("Volume '{}' added", volume.name)).into_response()
+}
+
+async fn remove_volume(
+    State(index): State<ContentIndex>,
+    current: Option<CurrentUser>,
+    UrlPath(name): UrlPath<String>,
+) -> impl IntoResponse {
+    if let Err(e) = admins_only(current.as_ref()) {
+        return e.into_response();
+    }
+    if config::get().volumes.iter().any(|v| v.name == name) {
+        return (StatusCode::FORBIDDEN, "Volumes from the configuration can't be removed").into_response();
+    }
+    let Some(volume) = sandbox::remove_volume(&name) else {
+        return (StatusCode::NOT_FOUND, "No such volume").into_response();
+    };
+    tracing::info!("volume '{}' removed", volume.name);
+    index.remove(&volume.path);
+    (StatusCode::OK, format!("Volume '{}' removed", volume.name)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_mountinfo_line() {
+        let line = "36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue";
+        let mount = parse_line(line).unwrap();
+        assert_eq!(mount.device, "98:0");
+        assert_eq!(mount.source, "/dev/root");
+        assert_eq!(mount.mount_point, PathBuf::from("/mnt2"));
+        assert_eq!(mount.fs_type, "ext3");
+        assert_eq!(mount.options, ["rw", "noatime"]);
+        assert_eq!(mount.super_options, ["rw", "errors=continue"]);
+    }
+
+    #[test]
+    fn optional_fields_may_be_missing_or_many() {
+        let none = parse_line("22 1 0:21 / /proc rw,nosuid - proc proc rw").unwrap();
+        assert_eq!(none.mount_point, PathBuf::from("/proc"));
+        assert_eq!(none.fs_type, "proc");
+        let many = parse_line("40 22 8:1 / /data rw shared:7 master:2 propagate_from:3 - xfs /dev/sda1 rw").unwrap();
+        assert_eq!(many.mount_point, PathBuf::from("/data"));
+        assert_eq!(many.source, "/dev/sda1");
+    }
+
+    #[test]
+    fn escaped_paths_are_decoded() {
+        let mount = parse_line(r"50 22 8:2 / /media/My\040Disk rw - vfat /dev/sdb1 rw").unwrap();
+        assert_eq!(mount.mount_point, PathBuf::from("/media/My Disk"));
+        assert_eq!(unescape(r"a\011b\012c\134d"), "a\tb\nc\\d");
+    }
+
+    #[test]
+    fn broken_escapes_are_kept_as_they_are() {
+        assert_eq!(unescape(r"\09"), r"\09");
+        assert_eq!(unescape(r"end\"), r"end\");
+        assert_eq!(unescape(r"\x41"), r"\x41");
+    }
+
+    #[test]
+    fn lines_without_a_separator_are_skipped() {
+        assert!(parse_line("").is_none());
+        assert!(parse_line("36 35 98:0 /mnt1 /mnt2 rw,noatime ext3 /dev/root rw").is_none());
+        assert!(parse_line("36 35 - ext3 /dev/root rw").is_none());
+    }
+}
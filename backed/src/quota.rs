@@ -0,0 +1,187 @@
+// Storage accounting for home folders. A user's usage is the size of their
+// home on every writable volume, plus what they deleted into the trash until
+// it is purged and the earlier versions of their files. It is measured once
+// when first needed and then kept current by the handlers that write or
+// delete, which either adjust it by the bytes they know about or drop it to
+// be measured again.
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::{Component, Path},
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    auth::{self, CurrentUser},
+    config, fs_ops, sandbox, trash, versions,
+};
+
+static USAGE: LazyLock<Mutex<HashMap<String, u64>>> = LazyLock::new(Default::default);
+
+// The user whose home `path` is in, if any
+pub fn owner_of(path: &Path) -> Option<String> {
+    let volume = sandbox::volume_of(path).filter(|v| !v.read_only)?;
+    let mut components = path.strip_prefix(&volume.path).ok()?.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(folder)), Some(Component::Normal(user))) if folder == config::get().homes.folder.as_str() => {
+            Some(user.to_string_lossy().to_string())
+        }
+        _ => None,
+    }
+}
+
+pub async fn usage(username: &str) -> u64 {
+    if let Some(&used) = USAGE.lock().unwrap().get(username) {
+        return used;
+    }
+    let homes: Vec<_> = sandbox::volumes()
+        .into_iter()
+        .filter(|v| !v.read_only)
+        .filter_map(|v| sandbox::home_dir(v, username).ok())
+        .collect();
+    let name = username.to_string();
+    let used = tokio::task::spawn_blocking(move || {
+        homes.iter().map(|h| fs_ops::tree_size(h)).sum::<u64>()
+            + trash::owned_bytes(&name)
+            + versions::owned_bytes(&name)
+    })
+    .await
+    .unwrap();
+    USAGE.lock().unwrap().insert(username.to_string(), used);
+    used
+}
+
+// Adjusts the owner's usage after `delta` bytes were written (or removed,
+// when negative) at `path`
+pub fn record(path: &Path, delta: i64) {
+    if let Some(owner) = owner_of(path) {
+        record_for(&owner, delta);
+    }
+}
+
+// Like `record`, for bytes charged to a user that sit outside their home
+pub fn record_for(username: &str, delta: i64) {
+    if let Some(used) = USAGE.lock().unwrap().get_mut(username) {
+        *used = used.saturating_add_signed(delta);
+    }
+}
+
+// Has the owner's usage measured again next time, for changes whose size
+// isn't known up front, such as moves and finished copies
+pub fn invalidate(path: &Path) {
+    if let Some(owner) = owner_of(path) {
+        USAGE.lock().unwrap().remove(&owner);
+    }
+}
+
+// Bytes the signed-in user may still write; `None` for no limit
+pub async fn remaining() -> Option<u64> {
+    let user = auth::current_user()?;
+    let quota = user.quota_bytes?;
+    Some(quota.saturating_sub(usage(&user.username).await))
+}
+
+// Refuses a write of `incoming` bytes that wouldn't fit in the user's quota
+pub async fn check(incoming: u64) -> Result<(), (StatusCode, String)> {
+    match remaining().await {
+        Some(left) if incoming > left => Err(exceeded()),
+        _ => Ok(()),
+    }
+}
+
+pub fn exceeded() -> (StatusCode, String) {
+    (StatusCode::INSUFFICIENT_STORAGE, "Storage quota exceeded".to_string())
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    used_bytes: u64,
+    // `null` when there is no limit
+    quota_bytes: Option<u64>,
+    available_bytes: Option<u64>,
+}
+
+pub async fn usage_report(user: Option<CurrentUser>) -> impl IntoResponse {
+    let Some(user) = user.filter(|u| u.confined) else {
+        return AxumJson(UsageReport { used_bytes: 0, quota_bytes: None, available_bytes: None }).into_response();
+    };
+    let used = usage(&user.username).await;
+    AxumJson(UsageReport {
+        used_bytes: used,
+        quota_bytes: user.quota_bytes,
+        available_bytes: user.quota_bytes.map(|q| q.saturating_sub(used)),
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{run_as, test_user};
+    use std::path::PathBuf;
+
+    // A fresh home with one file of `size` bytes in it
+    fn home_with_file(username: &str, size: usize) -> PathBuf {
+        let volume = &config::for_tests().volumes[0];
+        let home = sandbox::home_dir(volume, username).unwrap();
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(home.join("file"), vec![0; size]).unwrap();
+        home
+    }
+
+    #[test]
+    fn files_belong_to_the_home_they_are_in() {
+        let volume = &config::for_tests().volumes[0].path;
+        let homes = volume.join(&config::get().homes.folder);
+        assert_eq!(owner_of(&homes.join("bob/a/b.txt")).as_deref(), Some("bob"));
+        assert_eq!(owner_of(&homes.join("bob")).as_deref(), Some("bob"));
+        assert_eq!(owner_of(&homes), None);
+        assert_eq!(owner_of(&volume.join("shared/bob")), None);
+    }
+
+    #[tokio::test]
+    async fn usage_is_measured_once_then_kept_current() {
+        let home = home_with_file("quota_measure", 100);
+        assert_eq!(usage("quota_measure").await, 100);
+
+        std::fs::write(home.join("more"), vec![0; 50]).unwrap();
+        assert_eq!(usage("quota_measure").await, 100);
+        record(&home.join("more"), 50);
+        assert_eq!(usage("quota_measure").await, 150);
+        record(&home.join("more"), -500);
+        assert_eq!(usage("quota_measure").await, 0);
+
+        invalidate(&home);
+        assert_eq!(usage("quota_measure").await, 150);
+    }
+
+    #[tokio::test]
+    async fn writes_past_the_quota_are_refused() {
+        home_with_file("quota_check", 100);
+        let mut user = test_user("quota_check");
+        user.quota_bytes = Some(150);
+        run_as(Some(user.clone()), async {
+            assert_eq!(remaining().await, Some(50));
+            assert!(check(50).await.is_ok());
+            assert_eq!(check(51).await.unwrap_err().0, StatusCode::INSUFFICIENT_STORAGE);
+        })
+        .await;
+
+        user.quota_bytes = None;
+        run_as(Some(user), async { assert!(check(u64::MAX).await.is_ok()) }).await;
+    }
+
+    #[tokio::test]
+    async fn trashed_files_stay_charged_to_their_owner() {
+        let home = home_with_file("quota_trash", 100);
+        trash::move_to_trash(&home.join("file")).await.unwrap();
+        invalidate(&home);
+        assert_eq!(usage("quota_trash").await, 100);
+        assert_eq!(trash::owned_bytes("quota_trash"), 100);
+    }
+}
@@ -0,0 +1,326 @@
+use axum::http::{header, HeaderMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// More ranges than this in one request is treated as abuse and the header is ignored
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    // Inclusive, as in `Content-Range`
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    // No usable `Range` header: serve the whole representation with 200
+    Full,
+    // One or more satisfiable ranges, sorted and merged
+    Partial(Vec<ByteRange>),
+    // Syntactically valid but nothing overlaps the file: 416
+    Unsatisfiable,
+}
+
+// Works out which part of a `len` byte file the request asks for (RFC 9110 §14)
+pub fn from_headers(headers: &HeaderMap, len: u64, modified: Option<SystemTime>, etag: Option<&str>) -> RangeRequest {
+    let Some(value) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return RangeRequest::Full;
+    };
+
+    if !if_range_matches(headers, modified, etag) {
+        return RangeRequest::Full;
+    }
+
+    parse(value, len)
+}
+
+pub fn parse(value: &str, len: u64) -> RangeRequest {
+    let Some((unit, specs)) = value.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    let mut seen = 0;
+
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        seen += 1;
+        if seen > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+
+        if first.is_empty() {
+            // Suffix range: the last N bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix > 0 && len > 0 {
+                ranges.push(ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                });
+            }
+            continue;
+        }
+
+        let Ok(start) = first.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = if last.is_empty() {
+            u64::MAX
+        } else {
+            match last.parse::<u64>() {
+                Ok(end) if end >= start => end,
+                _ => return RangeRequest::Full,
+            }
+        };
+
+        if start < len {
+            ranges.push(ByteRange {
+                start,
+                end: end.min(len - 1),
+            });
+        }
+    }
+
+    if seen == 0 {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    RangeRequest::Partial(coalesce(ranges))
+}
+
+// Overlapping or adjacent ranges are merged so a client can't make us send
+// the same bytes many times over
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// Strong entity tag for a file. Size and modification time down to the
+// nanosecond change with every write the server makes, so they stand in for
+// hashing the content.
+pub fn etag(len: u64, modified: Option<SystemTime>) -> Option<String> {
+    let nanos = modified?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some(format!("\"{:x}-{:x}\"", len, nanos))
+}
+
+// Whether the client's cached copy is still current, so a 304 can be sent
+// instead of the file. `If-None-Match` wins over `If-Modified-Since` (RFC 9110 §13.2.2).
+pub fn not_modified(headers: &HeaderMap, modified: Option<SystemTime>, etag: Option<&str>) -> bool {
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(value) = value.to_str() else {
+            return false;
+        };
+        // Weak comparison: a `W/` prefix doesn't matter here
+        return value.split(',').map(str::trim).any(|tag| {
+            tag == "*" || etag.is_some_and(|etag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+        });
+    }
+
+    let since = headers.get(header::IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok());
+    match (since.map(|v| httpdate::parse_http_date(v.trim())), modified) {
+        (Some(Ok(date)), Some(modified)) => unix_secs(modified) <= unix_secs(date),
+        _ => false,
+    }
+}
+
+// `If-Range` only lets the range through when the validator still matches.
+// Entity tags have to match exactly; weak ones never do.
+fn if_range_matches(headers: &HeaderMap, modified: Option<SystemTime>, etag: Option<&str>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+
+    if value.starts_with("W/") {
+        return false;
+    }
+    if value.starts_with('"') {
+        return etag.is_some_and(|etag| etag == value);
+    }
+
+    match (httpdate::parse_http_date(value), modified) {
+        (Ok(date), Some(modified)) => unix_secs(date) == unix_secs(modified),
+        _ => false,
+    }
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+pub fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("disk_manager_{:x}{:x}", nanos, std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use std::time::Duration;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn parses_closed_open_and_suffix_ranges() {
+        assert_eq!(parse("bytes=0-9", 100), RangeRequest::Partial(vec![range(0, 9)]));
+        assert_eq!(parse("bytes=90-", 100), RangeRequest::Partial(vec![range(90, 99)]));
+        assert_eq!(parse("bytes=-10", 100), RangeRequest::Partial(vec![range(90, 99)]));
+        assert_eq!(parse("BYTES = 5 - 6", 100), RangeRequest::Partial(vec![range(5, 6)]));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(parse("bytes=50-500", 100), RangeRequest::Partial(vec![range(50, 99)]));
+        assert_eq!(parse("bytes=-500", 100), RangeRequest::Partial(vec![range(0, 99)]));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse("bytes=100-", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=200-300", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn satisfiable_ranges_are_kept_next_to_unsatisfiable_ones() {
+        assert_eq!(parse("bytes=200-300,0-0", 100), RangeRequest::Partial(vec![range(0, 0)]));
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for value in ["bytes", "items=0-1", "bytes=", "bytes=a-b", "bytes=5-3", "bytes=1", "bytes=-x"] {
+            assert_eq!(parse(value, 100), RangeRequest::Full, "{}", value);
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_are_ignored() {
+        let specs: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        let value = format!("bytes={}", specs.join(","));
+        assert_eq!(parse(&value, 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(parse("bytes=10-19,0-9,50-59", 100), RangeRequest::Partial(vec![range(0, 19), range(50, 59)]));
+        assert_eq!(parse("bytes=0-50,10-20,-60", 100), RangeRequest::Partial(vec![range(0, 99)]));
+        assert_eq!(coalesce(vec![range(5, 5), range(0, 3), range(4, 4)]), vec![range(0, 5)]);
+        assert_eq!(coalesce(vec![range(0, 3), range(5, 6)]), vec![range(0, 3), range(5, 6)]);
+        assert_eq!(coalesce(vec![range(0, u64::MAX), range(10, 20)]), vec![range(0, u64::MAX)]);
+    }
+
+    #[test]
+    fn etags_change_with_size_and_time() {
+        let time = UNIX_EPOCH + Duration::from_nanos(1_000_000_001);
+        let etag = etag(10, Some(time)).unwrap();
+        assert_eq!(etag, "\"a-3b9aca01\"");
+        assert_ne!(Some(etag.clone()), super::etag(11, Some(time)));
+        assert_ne!(Some(etag), super::etag(10, Some(time + Duration::from_nanos(1))));
+        assert_eq!(super::etag(10, None), None);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = Some("\"a-1\"");
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, "\"a-1\"")]), None, etag));
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, "W/\"a-1\"")]), None, etag));
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, "\"x\", \"a-1\"")]), None, etag));
+        assert!(not_modified(&headers(&[(header::IF_NONE_MATCH, "*")]), None, etag));
+        assert!(!not_modified(&headers(&[(header::IF_NONE_MATCH, "\"a-2\"")]), None, etag));
+        assert!(!not_modified(&headers(&[(header::IF_NONE_MATCH, "\"a-1\"")]), None, None));
+        assert!(!not_modified(&HeaderMap::new(), None, etag));
+    }
+
+    #[test]
+    fn if_modified_since_goes_by_whole_seconds() {
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(not_modified(&headers(&[(header::IF_MODIFIED_SINCE, date)]), Some(modified), None));
+        let earlier = "Sun, 06 Nov 1994 08:49:36 GMT";
+        assert!(!not_modified(&headers(&[(header::IF_MODIFIED_SINCE, earlier)]), Some(modified), None));
+        assert!(!not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "yesterday")]), Some(modified), None));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let modified = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let map = headers(&[
+            (header::IF_NONE_MATCH, "\"old\""),
+            (header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+        assert!(!not_modified(&map, Some(modified), Some("\"new\"")));
+    }
+
+    #[test]
+    fn if_range_needs_a_matching_validator() {
+        let modified = Some(UNIX_EPOCH + Duration::from_secs(784_111_777));
+        let etag = Some("\"a-1\"");
+        let check = |value: &str| if_range_matches(&headers(&[(header::IF_RANGE, value)]), modified, etag);
+        assert!(if_range_matches(&HeaderMap::new(), modified, etag));
+        assert!(check("\"a-1\""));
+        assert!(!check("\"a-2\""));
+        assert!(!check("W/\"a-1\""));
+        assert!(check("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!check("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(!check("garbage"));
+    }
+
+    #[test]
+    fn failed_if_range_serves_the_whole_file() {
+        let map = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"stale\"")]);
+        assert_eq!(from_headers(&map, 100, None, Some("\"fresh\"")), RangeRequest::Full);
+        let map = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"fresh\"")]);
+        assert_eq!(from_headers(&map, 100, None, Some("\"fresh\"")), RangeRequest::Partial(vec![range(0, 9)]));
+    }
+}
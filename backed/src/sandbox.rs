@@ -0,0 +1,423 @@
+// Maps client supplied paths onto a volume root. Paths are normalized
+// component by component and then resolved the way the OS would, symlinks
+// included, so nothing a handler touches can end up outside its volume.
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use std::{
+    fmt, io,
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+};
+
+use crate::{
+    auth,
+    config::{self, Volume},
+    trash::TRASH_DIR,
+    versions::VERSIONS_DIR,
+};
+
+// Same limit Linux puts on nested symlinks
+const MAX_SYMLINK_DEPTH: usize = 40;
+// Folders at the root of each volume that the server keeps for itself
+const RESERVED_DIRS: [&str; 2] = [TRASH_DIR, VERSIONS_DIR];
+
+// Volumes admins added while the server runs. They are leaked so they can be
+// handed out as `&'static` like the configured ones, and are gone after a restart.
+static ADDED: RwLock<Vec<&'static Volume>> = RwLock::new(Vec::new());
+
+#[derive(Debug)]
+pub enum PathError {
+    // A `..` component
+    ParentComponent,
+    // Drive letters, UNC shares and the like
+    Absolute,
+    InvalidName,
+    // Folders the server keeps for itself, such as the trash
+    Reserved,
+    EscapesRoot,
+    Symlink,
+    UnknownVolume,
+    ReadOnly,
+    Io(io::Error),
+}
+
+impl PathError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PathError::ParentComponent | PathError::Absolute | PathError::InvalidName => StatusCode::BAD_REQUEST,
+            PathError::Reserved | PathError::EscapesRoot | PathError::Symlink | PathError::ReadOnly => {
+                StatusCode::FORBIDDEN
+            }
+            PathError::UnknownVolume => StatusCode::NOT_FOUND,
+            PathError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::ParentComponent => write!(f, "Invalid path: '..' is not allowed"),
+            PathError::Absolute => write!(f, "Invalid path: drive and network prefixes are not allowed"),
+            PathError::InvalidName => write!(f, "Invalid path: name contains a forbidden character"),
+            PathError::Reserved => write!(f, "This folder is managed by the server"),
+            PathError::EscapesRoot => write!(f, "Path leads outside the volume"),
+            PathError::Symlink => write!(f, "Path goes through a symlink"),
+            PathError::UnknownVolume => write!(f, "No such volume"),
+            PathError::ReadOnly => write!(f, "This volume is read-only"),
+            PathError::Io(e) => write!(f, "Could not resolve path: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for PathError {
+    fn from(e: io::Error) -> Self {
+        PathError::Io(e)
+    }
+}
+
+impl IntoResponse for PathError {
+    fn into_response(self) -> Response {
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+// Configured volumes first, then those added at runtime
+pub fn volumes() -> Vec<&'static Volume> {
+    config::get().volumes.iter().chain(ADDED.read().unwrap().iter().copied()).collect()
+}
+
+// Fails when the name is taken
+pub fn add_volume(volume: Volume) -> Option<&'static Volume> {
+    let mut added = ADDED.write().unwrap();
+    let taken = config::get().volumes.iter().chain(added.iter().copied()).any(|v| v.name == volume.name);
+    if taken {
+        return None;
+    }
+    let volume: &'static Volume = Box::leak(Box::new(volume));
+    added.push(volume);
+    Some(volume)
+}
+
+// Only volumes added at runtime can be removed
+pub fn remove_volume(name: &str) -> Option<&'static Volume> {
+    let mut added = ADDED.write().unwrap();
+    let i = added.iter().position(|v| v.name == name)?;
+    Some(added.remove(i))
+}
+
+// The named volume, or the first one when no name is given
+pub fn volume(name: Option<&str>) -> Result<&'static Volume, PathError> {
+    let volumes = volumes();
+    match name.filter(|n| !n.is_empty()) {
+        Some(name) => volumes.into_iter().find(|v| v.name == name).ok_or(PathError::UnknownVolume),
+        None => Ok(volumes[0]),
+    }
+}
+
+// The volume a path returned by `resolve_path` belongs to
+pub fn volume_of(path: &Path) -> Option<&'static Volume> {
+    volumes()
+        .into_iter()
+        .filter(|v| path.starts_with(&v.path))
+        .max_by_key(|v| v.path.components().count())
+}
+
+// Refuses names that aren't a single plain folder name, so no account can
+// end up with the homes folder or the volume itself as its home
+pub fn home_dir(volume: &Volume, username: &str) -> Result<PathBuf, PathError> {
+    let mut components = Path::new(username).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == username && !username.starts_with('.') => {
+            Ok(volume.path.join(&config::get().homes.folder).join(username))
+        }
+        _ => Err(PathError::InvalidName),
+    }
+}
+
+// What paths on `volume` are relative to for the current request: the
+// volume itself, or the user's home when they are kept inside it. Read-only
+// volumes are shared, so nobody is confined there.
+fn request_root(volume: &Volume) -> Result<PathBuf, PathError> {
+    match auth::current_user() {
+        Some(user) if user.confined && !volume.read_only => home_dir(volume, &user.username),
+        _ => Ok(volume.path.clone()),
+    }
+}
+
+// Where `path` sits for the current request, `/` separated, ready to hand
+// back to clients
+pub fn relative_path(path: &Path) -> String {
+    let relative = volume_of(path)
+        .and_then(|v| {
+            let root = request_root(v).unwrap_or_else(|_| v.path.clone());
+            path.strip_prefix(root).or_else(|_| path.strip_prefix(&v.path)).ok()
+        })
+        .unwrap_or(path);
+    relative.to_string_lossy().replace('\\', "/")
+}
+
+// Where `path` sits inside its volume, whoever is asking
+pub fn volume_relative(path: &Path) -> String {
+    let relative = volume_of(path).and_then(|v| path.strip_prefix(&v.path).ok()).unwrap_or(path);
+    relative.to_string_lossy().replace('\\', "/")
+}
+
+// Folders the server keeps for itself, which walks over a volume skip
+pub fn is_reserved(path: &Path) -> bool {
+    volume_of(path).is_some_and(|v| {
+        path.strip_prefix(&v.path).is_ok_and(|rest| RESERVED_DIRS.iter().any(|dir| rest == Path::new(dir)))
+    })
+}
+
+// Where `path` really sits inside its volume once links are followed
+pub fn real_volume_relative(path: &Path) -> Option<(&'static Volume, String)> {
+    let volume = volume_of(path)?;
+    let root = volume.path.canonicalize().ok()?;
+    let real = real_path(&root.join(path.strip_prefix(&volume.path).ok()?), 0).ok()?;
+    let relative = real.strip_prefix(&root).ok()?;
+    Some((volume, relative.to_string_lossy().replace('\\', "/")))
+}
+
+// Whether a link met while walking a volume still lands inside the root the
+// current request is kept to once followed, and outside the folders the
+// server keeps for itself. Walks don't go through links, so each one they
+// come across has to pass this before it is read.
+pub fn link_stays_inside(path: &Path) -> bool {
+    if !config::get().follow_symlinks {
+        return false;
+    }
+    let Some(volume) = volume_of(path) else {
+        return false;
+    };
+    let root = request_root(volume).ok().and_then(|root| root.canonicalize().ok());
+    let (Ok(volume_root), Some(root)) = (volume.path.canonicalize(), root) else {
+        return false;
+    };
+    let Ok(rest) = path.strip_prefix(&volume.path) else {
+        return false;
+    };
+    real_path(&volume_root.join(rest), 0).is_ok_and(|real| real.starts_with(&root) && !in_reserved(&real, &volume_root))
+}
+
+fn in_reserved(real: &Path, volume_root: &Path) -> bool {
+    real.strip_prefix(volume_root)
+        .ok()
+        .and_then(|rest| rest.components().next())
+        .is_some_and(|first| RESERVED_DIRS.iter().any(|dir| first.as_os_str() == *dir))
+}
+
+// Like `resolve_path`, but refuses read-only volumes
+pub fn resolve_writable(volume_name: Option<&str>, subpath: Option<String>) -> Result<PathBuf, PathError> {
+    if volume(volume_name)?.read_only {
+        return Err(PathError::ReadOnly);
+    }
+    resolve_path(volume_name, subpath)
+}
+
+// Returns `<root>/<subpath>` once it is known to stay inside the root, where
+// the root is the volume or the user's home on it. The path is returned as
+// written rather than with links resolved, so callers can keep relating it
+// back to the root with `strip_prefix`.
+pub fn resolve_path(volume_name: Option<&str>, subpath: Option<String>) -> Result<PathBuf, PathError> {
+    let volume = volume(volume_name)?;
+    let base = request_root(volume)?;
+    if base != volume.path {
+        std::fs::create_dir_all(&base)?;
+    }
+    let sub = subpath.unwrap_or_default();
+
+    // `\\server\share` and `\\?\C:\` style prefixes
+    if sub.starts_with("\\\\") {
+        return Err(PathError::Absolute);
+    }
+
+    let mut path = base.clone();
+    let mut depth = 0;
+    // Windows clients send backslashes, and leading slashes just mean "the root"
+    for part in sub.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(PathError::ParentComponent),
+            _ if depth == 0 && is_drive(part) => return Err(PathError::Absolute),
+            _ if part.contains('\0') => return Err(PathError::InvalidName),
+            _ if depth == 0 && RESERVED_DIRS.contains(&part) => return Err(PathError::Reserved),
+            _ => {}
+        }
+        path.push(part);
+        depth += 1;
+    }
+
+    let root = base.canonicalize()?;
+    // With `follow_symlinks` off, any path that passes through a link is
+    // refused. With it on, links are followed as long as they stay inside.
+    if !config::get().follow_symlinks {
+        let mut current = root.clone();
+        for part in path.strip_prefix(&base).unwrap().components() {
+            current.push(part);
+            match std::fs::symlink_metadata(&current) {
+                Ok(m) if m.file_type().is_symlink() => return Err(PathError::Symlink),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+    let real = real_path(&root.join(path.strip_prefix(&base).unwrap()), 0)?;
+    if !real.starts_with(&root) {
+        return Err(PathError::EscapesRoot);
+    }
+    // A link can't lead into the trash either
+    if in_reserved(&real, &root) {
+        return Err(PathError::Reserved);
+    }
+    Ok(path)
+}
+
+fn is_drive(part: &str) -> bool {
+    let bytes = part.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+// Like `canonicalize`, except the path doesn't have to exist. Links are
+// followed where they exist, including dangling ones, which matters because
+// writing through a dangling link creates its target.
+fn real_path(path: &Path, depth: usize) -> Result<PathBuf, PathError> {
+    if depth > MAX_SYMLINK_DEPTH {
+        return Err(PathError::Io(io::Error::other("Too many levels of symbolic links")));
+    }
+
+    let mut real = PathBuf::new();
+    let mut missing = false;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => real.push(component),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                real.pop();
+                missing = false;
+            }
+            Component::Normal(part) => {
+                real.push(part);
+                // Below a missing entry nothing can be a link
+                if missing {
+                    continue;
+                }
+                match std::fs::symlink_metadata(&real) {
+                    Ok(m) if m.file_type().is_symlink() => {
+                        let target = std::fs::read_link(&real)?;
+                        real.pop();
+                        let joined = real.join(target);
+                        real = real_path(&joined, depth + 1)?;
+                    }
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => missing = true,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+    Ok(real)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, sync::LazyLock};
+
+    // On the test volume: docs/a.txt, .trash/, inside -> docs,
+    // outside -> <temp>, to_trash -> .trash
+    static ROOT: LazyLock<PathBuf> = LazyLock::new(|| {
+        let root = config::for_tests().volumes[0].path.clone();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join(TRASH_DIR)).unwrap();
+        fs::write(root.join("docs/a.txt"), "a").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            symlink("docs", root.join("inside")).unwrap();
+            symlink(std::env::temp_dir(), root.join("outside")).unwrap();
+            symlink(TRASH_DIR, root.join("to_trash")).unwrap();
+        }
+        root
+    });
+
+    fn resolve(subpath: &str) -> Result<PathBuf, PathError> {
+        LazyLock::force(&ROOT);
+        resolve_path(Some("data"), Some(subpath.to_string()))
+    }
+
+    #[test]
+    fn paths_are_joined_onto_the_volume() {
+        assert_eq!(resolve("docs/a.txt").unwrap(), ROOT.join("docs/a.txt"));
+        assert_eq!(resolve("/docs//./a.txt").unwrap(), ROOT.join("docs/a.txt"));
+        assert_eq!(resolve("docs\\a.txt").unwrap(), ROOT.join("docs/a.txt"));
+        assert_eq!(resolve("").unwrap(), *ROOT);
+        assert_eq!(resolve("docs/new/file.txt").unwrap(), ROOT.join("docs/new/file.txt"));
+    }
+
+    #[test]
+    fn escapes_are_refused() {
+        assert!(matches!(resolve("docs/../../etc"), Err(PathError::ParentComponent)));
+        assert!(matches!(resolve(".."), Err(PathError::ParentComponent)));
+        assert!(matches!(resolve("C:/Windows"), Err(PathError::Absolute)));
+        assert!(matches!(resolve("\\\\server\\share"), Err(PathError::Absolute)));
+        assert!(matches!(resolve("docs/a\0b"), Err(PathError::InvalidName)));
+        assert!(matches!(resolve_path(Some("nope"), None), Err(PathError::UnknownVolume)));
+    }
+
+    #[test]
+    fn reserved_folders_are_refused_at_the_root_only() {
+        assert!(matches!(resolve(".trash"), Err(PathError::Reserved)));
+        assert!(matches!(resolve("/.versions/x"), Err(PathError::Reserved)));
+        assert_eq!(resolve("docs/.trash").unwrap(), ROOT.join("docs/.trash"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_are_followed_only_while_they_stay_inside() {
+        assert_eq!(resolve("inside/a.txt").unwrap(), ROOT.join("inside/a.txt"));
+        assert!(matches!(resolve("outside"), Err(PathError::EscapesRoot)));
+        assert!(matches!(resolve("outside/anything"), Err(PathError::EscapesRoot)));
+        assert!(matches!(resolve("to_trash"), Err(PathError::Reserved)));
+        assert!(link_stays_inside(&ROOT.join("inside")));
+        assert!(!link_stays_inside(&ROOT.join("outside")));
+        assert!(!link_stays_inside(&ROOT.join("to_trash")));
+    }
+
+    #[test]
+    fn confined_users_are_kept_to_their_home() {
+        let user = auth::CurrentUser {
+            username: "bob".to_string(),
+            is_admin: false,
+            confined: true,
+            quota_bytes: None,
+            groups: Vec::new(),
+        };
+        let home = ROOT.join(&config::get().homes.folder).join("bob");
+        auth::with_user(Some(user), || {
+            assert_eq!(resolve("notes.txt").unwrap(), home.join("notes.txt"));
+            assert!(home.is_dir());
+            assert!(matches!(resolve("../alice"), Err(PathError::ParentComponent)));
+        });
+    }
+
+    #[test]
+    fn homes_need_a_plain_name() {
+        let volume = Volume { name: "data".to_string(), path: PathBuf::from("/v"), read_only: false };
+        LazyLock::force(&ROOT);
+        assert!(home_dir(&volume, "bob").is_ok());
+        for name in ["", ".", "..", ".hidden", "a/b", "/abs"] {
+            assert!(matches!(home_dir(&volume, name), Err(PathError::InvalidName)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn drives_and_real_paths() {
+        assert!(is_drive("C:") && is_drive("z:stuff"));
+        assert!(!is_drive("C") && !is_drive("1:") && !is_drive("ab"));
+        let missing = Path::new("/definitely/not/here/../there");
+        assert_eq!(real_path(missing, 0).unwrap(), PathBuf::from("/definitely/not/there"));
+    }
+}
@@ -0,0 +1,247 @@
+// Recursive filename search. Matches are streamed back as newline-delimited
+// JSON while the tree is still being walked; dropping the connection stops
+// the walk.
+use axum::{
+    body::{Body, Bytes},
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::{io, path::Path, time::UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::{
+    acl::{self, Permission},
+    auth, resolve_path, sandbox,
+};
+
+const DEFAULT_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MatchMode {
+    #[default]
+    Substring,
+    Glob,
+    Regex,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum EntryType {
+    #[default]
+    Any,
+    File,
+    Dir,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    // Folder to search under, the volume root by default
+    path: Option<String>,
+    volume: Option<String>,
+    q: String,
+    #[serde(default)]
+    mode: MatchMode,
+    #[serde(default)]
+    case_sensitive: bool,
+    #[serde(default, rename = "type")]
+    entry_type: EntryType,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    // Unix seconds, inclusive
+    modified_after: Option<u64>,
+    modified_before: Option<u64>,
+    // Descend into dot folders and report dot files
+    #[serde(default)]
+    hidden: bool,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    // Relative to the volume root, ready to pass to `/download` or `/list`
+    path: String,
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<u64>,
+}
+
+enum Matcher {
+    Substring { needle: String, case_sensitive: bool },
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(query: &SearchQuery) -> Result<Self, (StatusCode, String)> {
+        Ok(match query.mode {
+            MatchMode::Substring => Matcher::Substring {
+                needle: if query.case_sensitive { query.q.clone() } else { query.q.to_lowercase() },
+                case_sensitive: query.case_sensitive,
+            },
+            MatchMode::Glob => Matcher::Glob(
+                GlobBuilder::new(&query.q)
+                    .case_insensitive(!query.case_sensitive)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid glob: {}", e)))?
+                    .compile_matcher(),
+            ),
+            MatchMode::Regex => Matcher::Regex(
+                RegexBuilder::new(&query.q)
+                    .case_insensitive(!query.case_sensitive)
+                    .size_limit(1 << 20)
+                    .build()
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid regex: {}", e)))?,
+            ),
+        })
+    }
+
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            Matcher::Substring { needle, case_sensitive: true } => name.contains(needle.as_str()),
+            Matcher::Substring { needle, case_sensitive: false } => name.to_lowercase().contains(needle.as_str()),
+            Matcher::Glob(glob) => glob.is_match(name),
+            Matcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+pub async fn search(Query(query): Query<SearchQuery>) -> Response {
+    let root = match resolve_path(query.volume.as_deref(), query.path.clone()) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check(&root, Permission::Read) {
+        return e.into_response();
+    }
+    if !root.is_dir() {
+        return (StatusCode::NOT_FOUND, "Folder not found").into_response();
+    }
+    let matcher = match Matcher::new(&query) {
+        Ok(m) => m,
+        Err(e) => return e.into_response(),
+    };
+    let (tx, rx) = mpsc::channel(64);
+    let user = auth::current_user();
+    tokio::task::spawn_blocking(move || auth::with_user(user, || walk(&root, &matcher, &query, tx)));
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+fn walk(root: &Path, matcher: &Matcher, query: &SearchQuery, tx: mpsc::Sender<io::Result<Bytes>>) {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let mut found = 0;
+
+    let entries = WalkDir::new(root)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| (query.hidden || !is_hidden(e)) && !sandbox::is_reserved(e.path()));
+
+    for entry in entries {
+        if found >= limit || tx.is_closed() {
+            return;
+        }
+        // Unreadable folders are skipped rather than ending the search
+        let Ok(entry) = entry else { continue };
+
+        let name = entry.file_name().to_string_lossy();
+        if !matcher.is_match(&name) || !acl::allows(entry.path(), Permission::Read) {
+            continue;
+        }
+        let Some(hit) = to_hit(&entry, query) else {
+            continue;
+        };
+
+        let mut line = serde_json::to_vec(&hit).unwrap();
+        line.push(b'\n');
+        if tx.blocking_send(Ok(Bytes::from(line))).is_err() {
+            return;
+        }
+        found += 1;
+    }
+}
+
+// Applies the type, size and date filters, which need the entry's metadata
+fn to_hit(entry: &DirEntry, query: &SearchQuery) -> Option<SearchHit> {
+    let is_dir = entry.file_type().is_dir();
+    match query.entry_type {
+        EntryType::File if is_dir => return None,
+        EntryType::Dir if !is_dir => return None,
+        _ => {}
+    }
+
+    let metadata = entry.metadata().ok()?;
+    let size = if is_dir { 0 } else { metadata.len() };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    if query.min_size.is_some_and(|min| size < min) || query.max_size.is_some_and(|max| size > max) {
+        return None;
+    }
+    if let Some(after) = query.modified_after
+        && modified.is_none_or(|m| m < after)
+    {
+        return None;
+    }
+    if let Some(before) = query.modified_before
+        && modified.is_none_or(|m| m > before)
+    {
+        return None;
+    }
+
+    Some(SearchHit {
+        path: sandbox::relative_path(entry.path()),
+        name: entry.file_name().to_string_lossy().to_string(),
+        is_dir,
+        size,
+        modified,
+    })
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn query(json: serde_json::Value) -> SearchQuery {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bad_patterns_are_a_bad_request() {
+        config::for_tests();
+        for mode in ["regex", "glob"] {
+            let response = search(Query(query(serde_json::json!({"q": "[a-", "mode": mode})))).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn names_match_by_mode() {
+        let matcher = |json| Matcher::new(&query(json)).unwrap();
+        assert!(matcher(serde_json::json!({"q": "REP"})).is_match("report.pdf"));
+        let sensitive = matcher(serde_json::json!({"q": "REP", "case_sensitive": true}));
+        assert!(!sensitive.is_match("report.pdf"));
+        assert!(matcher(serde_json::json!({"q": "*.pdf", "mode": "glob"})).is_match("Report.PDF"));
+        assert!(matcher(serde_json::json!({"q": "^r.*t\\.", "mode": "regex"})).is_match("report.pdf"));
+    }
+}
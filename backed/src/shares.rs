@@ -0,0 +1,473 @@
+// Share links. A share is a random token that gives anyone holding it
+// read access to one file or folder, without an account. Shares can expire,
+// need a password and stop after a number of downloads. The public routes
+// under `/s` only ever resolve paths inside the shared entry.
+use axum::{
+    extract::{Json, Path as UrlPath, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json as AxumJson, Response},
+    routing::{delete, get},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::{
+    acl::{self, Permission},
+    auth::{self, CurrentUser},
+    config, fs_ops,
+    links::{self, Link},
+    listing::ListQuery,
+    sandbox::{self, resolve_path},
+};
+
+// Lets clients send the password without it ending up in URLs and logs
+const PASSWORD_HEADER: &str = "x-share-password";
+
+static SHARES: LazyLock<Mutex<Vec<Share>>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Share {
+    token: String,
+    // Who created it; `None` with auth turned off
+    owner: Option<String>,
+    volume: String,
+    // Relative to the volume root
+    path: String,
+    is_dir: bool,
+    // Unix seconds
+    created_at: u64,
+    expires_at: Option<u64>,
+    password_hash: Option<String>,
+    max_downloads: Option<u64>,
+    #[serde(default)]
+    downloads: u64,
+}
+
+impl Link for Share {
+    fn closed(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= auth::unix_now())
+            || self.max_downloads.is_some_and(|max| self.downloads >= max)
+    }
+}
+
+impl Share {
+    fn name(&self) -> String {
+        self.path.rsplit('/').next().filter(|n| !n.is_empty()).unwrap_or(&self.volume).to_string()
+    }
+
+    // Whether `user` may see and revoke the share
+    fn managed_by(&self, user: Option<&CurrentUser>) -> bool {
+        match user {
+            Some(user) => user.is_admin || self.owner.as_deref() == Some(user.username.as_str()),
+            None => true,
+        }
+    }
+}
+
+// Reads the shares file, if there is one yet
+pub fn load() -> io::Result<()> {
+    *SHARES.lock().unwrap() = fs_ops::read_json_or_default(&config::get().links.shares_file)?;
+    Ok(())
+}
+
+fn save(shares: &[Share]) -> io::Result<()> {
+    fs_ops::write_json_atomic(&config::get().links.shares_file, shares)
+}
+
+// Managing shares, for signed-in users
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(list_shares).post(create_share))
+        .route("/:token", delete(revoke_share))
+}
+
+// What people holding a link can reach
+pub fn public_router() -> Router {
+    Router::new()
+        .route("/:token", get(share_info))
+        .route("/:token/list", get(share_list))
+        .route("/:token/download", get(share_download))
+}
+
+#[derive(Serialize)]
+struct ShareInfo {
+    token: String,
+    url: String,
+    owner: Option<String>,
+    volume: String,
+    // Relative to the caller's root
+    path: String,
+    name: String,
+    is_dir: bool,
+    created_at: u64,
+    expires_at: Option<u64>,
+    has_password: bool,
+    max_downloads: Option<u64>,
+    downloads: u64,
+}
+
+impl From<&Share> for ShareInfo {
+    fn from(share: &Share) -> Self {
+        let path = match sandbox::volume(Some(&share.volume)) {
+            Ok(volume) => sandbox::relative_path(&volume.path.join(&share.path)),
+            Err(_) => share.path.clone(),
+        };
+        ShareInfo {
+            token: share.token.clone(),
+            url: format!("/s/{}", share.token),
+            owner: share.owner.clone(),
+            volume: share.volume.clone(),
+            path,
+            name: share.name(),
+            is_dir: share.is_dir,
+            created_at: share.created_at,
+            expires_at: share.expires_at,
+            has_password: share.password_hash.is_some(),
+            max_downloads: share.max_downloads,
+            downloads: share.downloads,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateShareReq {
+    path: String,
+    volume: Option<String>,
+    // Seconds from now; the link never expires when absent
+    expires_in_secs: Option<u64>,
+    password: Option<String>,
+    max_downloads: Option<u64>,
+}
+
+async fn create_share(user: Option<CurrentUser>, Json(payload): Json<CreateShareReq>) -> impl IntoResponse {
+    let path = match resolve_path(payload.volume.as_deref(), Some(payload.path)) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    // A link to a folder hands out everything in it
+    if let Err(e) = acl::check_tree(&path, Permission::Read).and_then(|_| acl::check_tree(&path, Permission::Share)) {
+        return e.into_response();
+    }
+    if !path.exists() {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    if payload.expires_in_secs == Some(0) || payload.max_downloads == Some(0) {
+        return (StatusCode::BAD_REQUEST, "Expiry and download limit must be greater than 0").into_response();
+    }
+    if payload.password.as_ref().is_some_and(|p| p.is_empty()) {
+        return (StatusCode::BAD_REQUEST, "Password must not be empty").into_response();
+    }
+    let Some((volume, relative)) = sandbox::real_volume_relative(&path) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Could not resolve path").into_response();
+    };
+
+    let password_hash = match payload.password {
+        Some(password) => match tokio::task::spawn_blocking(move || auth::hash_password(&password)).await.unwrap() {
+            Ok(hash) => Some(hash),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        None => None,
+    };
+    let now = auth::unix_now();
+    let share = Share {
+        token: auth::random_hex(16),
+        owner: user.map(|u| u.username),
+        volume: volume.name.clone(),
+        path: relative,
+        is_dir: path.is_dir(),
+        created_at: now,
+        expires_at: payload.expires_in_secs.map(|secs| now.saturating_add(secs)),
+        password_hash,
+        max_downloads: payload.max_downloads,
+        downloads: 0,
+    };
+
+    let info = ShareInfo::from(&share);
+    match links::add(&mut SHARES.lock().unwrap(), share, &config::get().links.shares_file) {
+        Ok(()) => (StatusCode::CREATED, AxumJson(info)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// The caller's own shares; admins see everyone's
+async fn list_shares(user: Option<CurrentUser>) -> impl IntoResponse {
+    let mut shares: Vec<ShareInfo> = SHARES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|s| !s.closed() && s.managed_by(user.as_ref()))
+        .map(ShareInfo::from)
+        .collect();
+    shares.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+    AxumJson(shares)
+}
+
+async fn revoke_share(user: Option<CurrentUser>, UrlPath(token): UrlPath<String>) -> impl IntoResponse {
+    let mut shares = SHARES.lock().unwrap();
+    let Some(position) = shares.iter().position(|s| s.token == token && s.managed_by(user.as_ref())) else {
+        return (StatusCode::NOT_FOUND, "No such share").into_response();
+    };
+    let removed = shares.remove(position);
+    match save(&shares) {
+        Ok(()) => (StatusCode::OK, "Share revoked").into_response(),
+        Err(e) => {
+            shares.insert(position, removed);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+// Finds a usable share and checks its password
+async fn open_share(token: &str, headers: &HeaderMap, password: Option<String>) -> Result<Share, (StatusCode, String)> {
+    let share = SHARES.lock().unwrap().iter().find(|s| s.token == token).cloned();
+    let Some(share) = share else {
+        return Err((StatusCode::NOT_FOUND, "No such share".to_string()));
+    };
+    if share.closed() {
+        return Err((StatusCode::GONE, "This link has expired".to_string()));
+    }
+
+    if let Some(hash) = share.password_hash.clone() {
+        let password = headers
+            .get(PASSWORD_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .or(password);
+        let Some(password) = password else {
+            return Err((StatusCode::UNAUTHORIZED, "This link needs a password".to_string()));
+        };
+        let valid = tokio::task::spawn_blocking(move || auth::verify_password(&hash, &password)).await.unwrap();
+        if !valid {
+            return Err((StatusCode::UNAUTHORIZED, "Wrong password".to_string()));
+        }
+    }
+    Ok(share)
+}
+
+// Resolves `subpath` inside the shared entry. A file share has nothing below it.
+fn share_path(share: &Share, subpath: Option<String>) -> Result<PathBuf, (StatusCode, String)> {
+    let subpath = subpath.unwrap_or_default();
+    let inner = subpath.split(['/', '\\']).any(|p| !p.is_empty() && p != ".");
+    if inner && !share.is_dir {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    }
+    let path = resolve_path(Some(&share.volume), Some(format!("{}/{}", share.path, subpath)))
+        .map_err(|e| (e.status(), e.to_string()))?;
+
+    // Links inside the shared folder may point elsewhere in the volume
+    let inside = sandbox::real_volume_relative(&path).is_some_and(|(_, relative)| {
+        share.path.is_empty()
+            || relative == share.path
+            || relative.strip_prefix(share.path.as_str()).is_some_and(|rest| rest.starts_with('/'))
+    });
+    if !inside {
+        return Err((StatusCode::FORBIDDEN, "Path leads outside the share".to_string()));
+    }
+    if sandbox::is_reserved(&path) {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    }
+    Ok(path)
+}
+
+#[derive(Deserialize)]
+struct ShareQuery {
+    // Inside a shared folder
+    path: Option<String>,
+    // For links that can't set `X-Share-Password`, such as ones opened in a browser
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PublicShareInfo {
+    name: String,
+    is_dir: bool,
+    size: Option<u64>,
+    expires_at: Option<u64>,
+    downloads_left: Option<u64>,
+}
+
+async fn share_info(
+    UrlPath(token): UrlPath<String>,
+    Query(query): Query<ShareQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let share = match open_share(&token, &headers, query.password).await {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let path = match share_path(&share, None) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    let size = if share.is_dir { None } else { tokio::fs::metadata(&path).await.ok().map(|m| m.len()) };
+    AxumJson(PublicShareInfo {
+        name: share.name(),
+        is_dir: share.is_dir,
+        size,
+        expires_at: share.expires_at,
+        downloads_left: share.max_downloads.map(|max| max.saturating_sub(share.downloads)),
+    })
+    .into_response()
+}
+
+async fn share_list(
+    UrlPath(token): UrlPath<String>,
+    Query(query): Query<ShareQuery>,
+    Query(list): Query<ListQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let share = match open_share(&token, &headers, query.password).await {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    if !share.is_dir {
+        return (StatusCode::BAD_REQUEST, "Not a folder").into_response();
+    }
+    match share_path(&share, query.path) {
+        Ok(path) => crate::list_dir(&path, &list).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn share_download(
+    UrlPath(token): UrlPath<String>,
+    Query(query): Query<ShareQuery>,
+    mut headers: HeaderMap,
+) -> Response {
+    let share = match open_share(&token, &headers, query.password).await {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let path = match share_path(&share, query.path) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+
+    // Any part of a file can be fetched with the right ranges, so on a link
+    // with a download limit every request counts and gets the whole entry,
+    // never a part of it or a 304
+    if share.max_downloads.is_some() && path.exists() {
+        for name in [header::RANGE, header::IF_RANGE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE] {
+            headers.remove(name);
+        }
+        let mut shares = SHARES.lock().unwrap();
+        let Some(current) = shares.iter_mut().find(|s| s.token == share.token) else {
+            return (StatusCode::NOT_FOUND, "No such share").into_response();
+        };
+        // Another request may have used up the last download meanwhile
+        if current.closed() {
+            return (StatusCode::GONE, "This link has expired").into_response();
+        }
+        current.downloads += 1;
+        if let Err(e) = save(&shares) {
+            tracing::warn!("could not record share download: {}", e);
+        }
+    }
+
+    crate::send_path(&path, &headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::HeaderValue};
+
+    // A file with `content` in a fresh folder, shared with `options` added to the request
+    async fn share_file(folder: &str, content: &str, options: serde_json::Value) -> String {
+        let dir = config::for_tests().volumes[0].path.join(folder);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.txt"), content).unwrap();
+
+        let mut request = serde_json::json!({"path": format!("{}/file.txt", folder)});
+        request.as_object_mut().unwrap().extend(options.as_object().unwrap().clone());
+        let response = create_share(None, Json(serde_json::from_value(request).unwrap())).await.into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["token"].as_str().unwrap().to_string()
+    }
+
+    fn no_query() -> Query<ShareQuery> {
+        Query(ShareQuery { path: None, password: None })
+    }
+
+    async fn download(token: &str, headers: HeaderMap) -> Response {
+        share_download(UrlPath(token.to_string()), no_query(), headers).await
+    }
+
+    #[tokio::test]
+    async fn limited_links_count_every_download_in_full() {
+        let token = share_file("share_limited", "0123456789", serde_json::json!({"max_downloads": 2})).await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=0-0"));
+
+        for _ in 0..2 {
+            let response = download(&token, headers.clone()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(&to_bytes(response.into_body(), usize::MAX).await.unwrap()[..], b"0123456789");
+        }
+        assert_eq!(download(&token, headers).await.status(), StatusCode::GONE);
+        let info = share_info(UrlPath(token), no_query(), HeaderMap::new()).await;
+        assert_eq!(info.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn unlimited_links_serve_ranges() {
+        let token = share_file("share_unlimited", "0123456789", serde_json::json!({})).await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=2-4"));
+        let response = download(&token, headers).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&to_bytes(response.into_body(), usize::MAX).await.unwrap()[..], b"234");
+    }
+
+    #[tokio::test]
+    async fn passwords_are_checked() {
+        let token = share_file("share_password", "secret", serde_json::json!({"password": "hunter22"})).await;
+        assert_eq!(download(&token, HeaderMap::new()).await.status(), StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(PASSWORD_HEADER, HeaderValue::from_static("wrong"));
+        assert_eq!(download(&token, headers.clone()).await.status(), StatusCode::UNAUTHORIZED);
+        headers.insert(PASSWORD_HEADER, HeaderValue::from_static("hunter22"));
+        assert_eq!(download(&token, headers).await.status(), StatusCode::OK);
+
+        let query = Query(ShareQuery { path: None, password: Some("hunter22".to_string()) });
+        assert_eq!(share_download(UrlPath(token), query, HeaderMap::new()).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn file_shares_have_nothing_below_them() {
+        let token = share_file("share_file_only", "x", serde_json::json!({})).await;
+        let share = SHARES.lock().unwrap().iter().find(|s| s.token == token).cloned().unwrap();
+        assert_eq!(share_path(&share, Some("other.txt".to_string())).unwrap_err().0, StatusCode::NOT_FOUND);
+        assert_eq!(share_path(&share, Some("../x".to_string())).unwrap_err().0, StatusCode::NOT_FOUND);
+        assert!(share_path(&share, Some("./".to_string())).is_ok());
+    }
+
+    #[test]
+    fn links_close_when_expired_or_used_up() {
+        let share = |expires_at, max_downloads, downloads| Share {
+            token: String::new(),
+            owner: None,
+            volume: "data".to_string(),
+            path: String::new(),
+            is_dir: true,
+            created_at: 0,
+            expires_at,
+            password_hash: None,
+            max_downloads,
+            downloads,
+        };
+        let now = auth::unix_now();
+        assert!(!share(None, None, 100).closed());
+        assert!(!share(Some(now + 60), Some(2), 1).closed());
+        assert!(share(Some(now.saturating_sub(1)), None, 0).closed());
+        assert!(share(None, Some(2), 2).closed());
+    }
+}
@@ -0,0 +1,400 @@
+// Recycle bin. Deleted entries are moved into `.trash` inside the root of
+// their volume, each under its own id next to a JSON record of where it came
+// from.
+use axum::{
+    extract::{Json, Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Json as AxumJson},
+    routing::{delete, get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::fs;
+
+use crate::{
+    acl::{self, Permission},
+    auth::{self, unix_now},
+    config::{self, Volume},
+    content_index::ContentIndex,
+    disk_usage, fs_ops, quota,
+    sandbox::{self, resolve_writable, PathError},
+};
+
+pub const TRASH_DIR: &str = ".trash";
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct TrashItem {
+    id: String,
+    #[serde(default)]
+    volume: String,
+    // Relative to the volume root; shown relative to the caller's root
+    original_path: String,
+    // Whose home the item was deleted from
+    #[serde(default)]
+    owner: Option<String>,
+    name: String,
+    is_dir: bool,
+    size: u64,
+    // Unix seconds
+    deleted_at: u64,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RestoreConflict {
+    // Refuse when something already lives at the original path
+    #[default]
+    Fail,
+    // Restore under a free name such as `report (1).txt`
+    Rename,
+    Overwrite,
+}
+
+#[derive(Default, Deserialize)]
+struct RestoreReq {
+    #[serde(default)]
+    on_conflict: RestoreConflict,
+}
+
+pub fn router(index: ContentIndex) -> Router {
+    Router::new()
+        .route("/", get(list_trash).delete(empty_trash))
+        .route("/:id", delete(purge_item))
+        .route("/:id/restore", post(restore_item))
+        .with_state(index)
+}
+
+fn trash_dir(volume: &Volume) -> PathBuf {
+    volume.path.join(TRASH_DIR)
+}
+
+fn info_path(volume: &Volume, id: &str) -> PathBuf {
+    trash_dir(volume).join(format!("{}.json", id))
+}
+
+// Moves `path` into the trash of its volume, returning the new item's id
+pub async fn move_to_trash(path: &Path) -> io::Result<String> {
+    let volume = sandbox::volume_of(path).ok_or_else(|| io::Error::other("Path is not on a volume"))?;
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let holder = trash_dir(volume).join(&id);
+
+    let (source, destination) = (path.to_path_buf(), holder.join(&name));
+    let (is_dir, size) = tokio::task::spawn_blocking(move || {
+        let is_dir = source.is_dir();
+        let size = fs_ops::tree_size(&source);
+        std::fs::create_dir_all(destination.parent().unwrap())?;
+        fs_ops::move_path(&source, &destination, false).map_err(|e| match e {
+            fs_ops::MoveError::Io(e) => e,
+            e => io::Error::other(e.to_string()),
+        })?;
+        Ok::<_, io::Error>((is_dir, size))
+    })
+    .await
+    .unwrap()?;
+
+    let item = TrashItem {
+        id: id.clone(),
+        volume: volume.name.clone(),
+        original_path: sandbox::volume_relative(path),
+        owner: quota::owner_of(path),
+        name,
+        is_dir,
+        size,
+        deleted_at: unix_now(),
+    };
+    fs::write(info_path(volume, &id), serde_json::to_vec(&item)?).await?;
+    Ok(id)
+}
+
+// Bytes of what was deleted from `username`'s home and is still in the
+// trash. They stay charged to the user until purged, or deleting and
+// uploading again would get around any quota.
+pub fn owned_bytes(username: &str) -> u64 {
+    sandbox::volumes()
+        .into_iter()
+        .filter_map(|volume| std::fs::read_dir(trash_dir(volume)).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|e| e == "json"))
+        .filter_map(|entry| std::fs::read(entry.path()).ok())
+        .filter_map(|data| serde_json::from_slice::<TrashItem>(&data).ok())
+        .filter(|item| item.owner.as_deref() == Some(username))
+        .map(|item| item.size)
+        .sum()
+}
+
+// Purges items older than `trash.keep_days` now and then for as long as the
+// server runs
+pub fn spawn_retention_purge() {
+    let Some(days) = config::get().trash.keep_days else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cutoff = unix_now().saturating_sub(days * 24 * 60 * 60);
+            for (volume, item) in load_items().await {
+                if item.deleted_at < cutoff && !volume.read_only {
+                    let _ = discard(volume, &item).await;
+                }
+            }
+        }
+    });
+}
+
+// Every trashed item across all volumes
+async fn load_items() -> Vec<(&'static Volume, TrashItem)> {
+    let mut items = Vec::new();
+    for volume in sandbox::volumes() {
+        let Ok(mut read_dir) = fs::read_dir(trash_dir(volume)).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            if entry.path().extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            if let Ok(data) = fs::read(entry.path()).await
+                && let Ok(mut item) = serde_json::from_slice::<TrashItem>(&data)
+            {
+                item.volume = volume.name.clone();
+                items.push((volume, item));
+            }
+        }
+    }
+    items
+}
+
+// Only trashed items visible to the caller
+async fn load_item(id: &str) -> Option<(&'static Volume, TrashItem)> {
+    if !fs_ops::is_stored_id(id) {
+        return None;
+    }
+    for volume in sandbox::volumes() {
+        if let Ok(data) = fs::read(info_path(volume, id)).await {
+            let mut item: TrashItem = serde_json::from_slice(&data).ok()?;
+            item.volume = volume.name.clone();
+            return Some((volume, item)).filter(|(volume, item)| visible(volume, item));
+        }
+    }
+    None
+}
+
+// Users kept to their home only see what was deleted from it, and nobody
+// sees items from places they can't read
+fn visible(volume: &Volume, item: &TrashItem) -> bool {
+    let own = match auth::current_user() {
+        Some(user) if user.confined => item.owner.as_deref() == Some(user.username.as_str()),
+        _ => true,
+    };
+    own && acl::allows(&volume.path.join(&item.original_path), Permission::Read)
+}
+
+// Where the item was deleted from, resolved for the caller
+fn original_location(volume: &Volume, item: &TrashItem) -> String {
+    sandbox::relative_path(&volume.path.join(&item.original_path))
+}
+
+async fn purge(volume: &Volume, id: &str) -> io::Result<()> {
+    let holder = trash_dir(volume).join(id);
+    if fs::metadata(&holder).await.is_ok() {
+        fs::remove_dir_all(&holder).await?;
+    }
+    fs::remove_file(info_path(volume, id)).await
+}
+
+// Purges an item for good, which frees its bytes for the user it was deleted from
+async fn discard(volume: &Volume, item: &TrashItem) -> io::Result<()> {
+    purge(volume, &item.id).await?;
+    if let Some(owner) = &item.owner {
+        quota::record_for(owner, -(item.size as i64));
+    }
+    Ok(())
+}
+
+async fn list_trash() -> impl IntoResponse {
+    let mut items: Vec<TrashItem> = load_items()
+        .await
+        .into_iter()
+        .filter(|(volume, item)| visible(volume, item))
+        .map(|(volume, mut item)| {
+            item.original_path = original_location(volume, &item);
+            item
+        })
+        .collect();
+    items.sort_by_key(|item| std::cmp::Reverse(item.deleted_at));
+    AxumJson(items)
+}
+
+async fn purge_item(UrlPath(id): UrlPath<String>) -> impl IntoResponse {
+    let Some((volume, item)) = load_item(&id).await else {
+        return (StatusCode::NOT_FOUND, "Not found in trash").into_response();
+    };
+    if volume.read_only {
+        return PathError::ReadOnly.into_response();
+    }
+    if let Err(e) = acl::check(&volume.path.join(&item.original_path), Permission::Delete) {
+        return e.into_response();
+    }
+    match discard(volume, &item).await {
+        Ok(()) => (StatusCode::OK, "Purged").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn empty_trash() -> impl IntoResponse {
+    // Items on read-only volumes, or from places the caller may not delete
+    // from, stay where they are
+    let items = load_items().await.into_iter().filter(|(v, item)| {
+        !v.read_only && visible(v, item) && acl::allows(&v.path.join(&item.original_path), Permission::Delete)
+    });
+    for (volume, item) in items {
+        if let Err(e) = discard(volume, &item).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    }
+    (StatusCode::OK, "Trash emptied").into_response()
+}
+
+async fn restore_item(
+    State(index): State<ContentIndex>,
+    UrlPath(id): UrlPath<String>,
+    payload: Option<Json<RestoreReq>>,
+) -> impl IntoResponse {
+    let Some((volume, item)) = load_item(&id).await else {
+        return (StatusCode::NOT_FOUND, "Not found in trash").into_response();
+    };
+    let policy = payload.map(|Json(p)| p.on_conflict).unwrap_or_default();
+
+    let mut target = match resolve_writable(Some(&item.volume), Some(original_location(volume, &item))) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = acl::check_tree(&target, Permission::Write) {
+        return e.into_response();
+    }
+    // Items are still charged to the user they were deleted from, so only
+    // restoring into someone else's home takes up more space
+    let charged = item.owner.is_some() && item.owner == quota::owner_of(&target);
+    if !charged && let Err(e) = quota::check(item.size).await {
+        return e.into_response();
+    }
+    let conflict = fs::symlink_metadata(&target).await.is_ok();
+    match policy {
+        RestoreConflict::Fail if conflict => {
+            return (StatusCode::CONFLICT, "Something already exists at the original path").into_response();
+        }
+        RestoreConflict::Rename if conflict => target = free_name(&target),
+        _ => {}
+    }
+
+    // The folder it was deleted from may be gone too
+    if let Some(parent) = target.parent()
+        && let Err(e) = fs::create_dir_all(parent).await
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    let source = trash_dir(volume).join(&item.id).join(&item.name);
+    let destination = target.clone();
+    let result = tokio::task::spawn_blocking(move || fs_ops::move_path(&source, &destination, true))
+        .await
+        .unwrap();
+    if let Err(e) = result {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    let _ = purge(volume, &item.id).await;
+    quota::invalidate(&target);
+    disk_usage::invalidate(&target);
+    index.refresh(target.clone());
+
+    (StatusCode::OK, sandbox::relative_path(&target)).into_response()
+}
+
+// `name (1).ext`, `name (2).ext`, ... whichever is free first
+pub fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| std::fs::symlink_metadata(candidate).is_err())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file in a fresh home, already moved to the trash
+    async fn trashed_file(username: &str, size: usize) -> (&'static Volume, TrashItem) {
+        config::for_tests();
+        let volume = sandbox::volumes()[0];
+        let home = sandbox::home_dir(volume, username).unwrap();
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(home.join("file"), vec![0; size]).unwrap();
+        let id = move_to_trash(&home.join("file")).await.unwrap();
+        load_item(&id).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_users_who_may_delete_can_purge() {
+        let (_, kept) = trashed_file("trash_reader", 10).await;
+        let home = format!("{}/trash_reader", config::get().homes.folder);
+        acl::add_test_rule(serde_json::json!({"path": home, "user": "trash_reader", "deny": ["delete"]}));
+        let mut reader = auth::test_user("trash_reader");
+        reader.confined = true;
+
+        let purge = purge_item(UrlPath(kept.id.clone()));
+        assert_eq!(auth::run_as(Some(reader.clone()), purge).await.into_response().status(), StatusCode::FORBIDDEN);
+        let emptied = auth::run_as(Some(reader), empty_trash()).await.into_response();
+        assert_eq!(emptied.status(), StatusCode::OK);
+        assert!(load_item(&kept.id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn emptying_skips_what_the_caller_may_not_delete() {
+        let (volume, _) = trashed_file("trash_partly", 10).await;
+        let home = volume.path.join(&config::get().homes.folder).join("trash_partly");
+        std::fs::create_dir_all(home.join("keep")).unwrap();
+        std::fs::write(home.join("keep/file"), "keep").unwrap();
+        let kept = move_to_trash(&home.join("keep/file")).await.unwrap();
+        let path = format!("{}/trash_partly/keep", config::get().homes.folder);
+        acl::add_test_rule(serde_json::json!({"path": path, "user": "trash_partly", "deny": ["delete"]}));
+        let mut user = auth::test_user("trash_partly");
+        user.confined = true;
+
+        auth::run_as(Some(user), empty_trash()).await;
+        assert!(load_item(&kept).await.is_some());
+        assert!(!load_items().await.iter().any(|(_, item)| {
+            item.owner.as_deref() == Some("trash_partly") && item.id != kept
+        }));
+    }
+
+    #[tokio::test]
+    async fn items_remember_where_they_came_from() {
+        let (_, item) = trashed_file("trash_origin", 10).await;
+        assert_eq!(item.owner.as_deref(), Some("trash_origin"));
+        assert_eq!(item.original_path, format!("{}/trash_origin/file", config::get().homes.folder));
+        assert_eq!(item.size, 10);
+        assert!(!item.is_dir);
+    }
+
+    #[tokio::test]
+    async fn discarding_frees_the_owners_bytes() {
+        let (volume, item) = trashed_file("trash_discard", 100).await;
+        assert_eq!(quota::usage("trash_discard").await, 100);
+        discard(volume, &item).await.unwrap();
+        assert_eq!(quota::usage("trash_discard").await, 0);
+        assert!(load_item(&item.id).await.is_none());
+        assert_eq!(owned_bytes("trash_discard"), 0);
+    }
+}
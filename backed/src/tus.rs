@@ -0,0 +1,576 @@
+// Resumable uploads following the tus 1.0 core protocol, plus the creation,
+// expiration and termination extensions: https://tus.io/protocols/resumable-upload
+use axum::{
+    body::Body,
+    extract::{Path as UrlPath, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{head, post},
+    Router,
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_stream::StreamExt;
+
+use crate::{
+    acl::{self, Permission},
+    auth, config,
+    content_index::ContentIndex,
+    disk_usage, fs_ops, quota,
+    sandbox::resolve_writable,
+    versions,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+
+// Uploads that see no PATCH for this long are swept away
+const UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+
+pub const EXPOSED_HEADERS: [HeaderName; 7] = [
+    header::LOCATION,
+    TUS_RESUMABLE,
+    TUS_VERSION_HEADER,
+    TUS_EXTENSION,
+    UPLOAD_OFFSET,
+    UPLOAD_LENGTH,
+    UPLOAD_EXPIRES,
+];
+
+#[derive(Clone)]
+struct TusState {
+    // Uploads with a PATCH in flight; a second writer is turned away
+    busy: Arc<Mutex<HashSet<String>>>,
+    index: ContentIndex,
+}
+
+struct BusyGuard {
+    state: TusState,
+    id: String,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.state.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TusState {
+    fn acquire(&self, id: &str) -> Option<BusyGuard> {
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+        Some(BusyGuard { state: self.clone(), id: id.to_string() })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UploadInfo {
+    length: u64,
+    // Target folder, as it would be passed to `/upload?path=`
+    path: String,
+    #[serde(default)]
+    volume: Option<String>,
+    filename: String,
+    // Who created the upload; nobody else may see or continue it
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+pub fn router(index: ContentIndex) -> Router {
+    Router::new()
+        .route("/", post(create_upload))
+        .route("/:id", head(upload_status).patch(append_chunk).delete(terminate_upload))
+        .with_state(TusState { busy: Default::default(), index })
+}
+
+// `OPTIONS /uploads` is answered by the CORS layer before it reaches a route,
+// so the tus capabilities are added to its response from the outside
+pub async fn advertise_capabilities(request: Request, next: Next) -> Response {
+    let is_discovery = request.method() == Method::OPTIONS && request.uri().path().starts_with("/uploads");
+    let mut response = next.run(request).await;
+    if is_discovery {
+        let headers = response.headers_mut();
+        headers.insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+        headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+        headers.insert(TUS_EXTENSION, HeaderValue::from_static(TUS_EXTENSIONS));
+    }
+    response
+}
+
+async fn create_upload(State(state): State<TusState>, headers: HeaderMap) -> Response {
+    if !supports_version(&headers) {
+        return version_mismatch();
+    }
+
+    let Some(length) = header_u64(&headers, &UPLOAD_LENGTH) else {
+        return tus_error(StatusCode::BAD_REQUEST, "Missing or invalid Upload-Length");
+    };
+
+    let metadata = match headers.get(&UPLOAD_METADATA).map(parse_metadata) {
+        Some(Ok(m)) => m,
+        Some(Err(e)) => return tus_error(StatusCode::BAD_REQUEST, e),
+        None => Vec::new(),
+    };
+    let lookup = |key: &str| metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+
+    let Some(filename) = lookup("filename").filter(|n| is_plain_file_name(n)) else {
+        return tus_error(StatusCode::BAD_REQUEST, "Upload-Metadata needs a valid filename");
+    };
+    let path = lookup("path").unwrap_or_default();
+    let volume = lookup("volume");
+    let target_dir = match resolve_writable(volume.as_deref(), Some(path.clone())) {
+        Ok(p) => p,
+        Err(e) => return tus_error(e.status(), e.to_string()),
+    };
+    if let Err((status, e)) = acl::check(&target_dir.join(&filename), Permission::Write) {
+        return tus_error(status, e);
+    }
+    if fs::symlink_metadata(target_dir.join(&filename)).await.is_ok_and(|m| m.is_dir()) {
+        let (status, e) = folder_in_the_way();
+        return tus_error(status, e);
+    }
+    // A file being replaced frees what it took up, unless it is kept as a version
+    let replaced = fs::metadata(target_dir.join(&filename)).await.map(|m| m.len()).unwrap_or(0);
+    let freed = if config::get().features.versions { 0 } else { replaced };
+    if let Err((status, e)) = quota::check(length.saturating_sub(freed)).await {
+        return tus_error(status, e);
+    }
+
+    sweep_expired().await;
+
+    if let Err(e) = fs::create_dir_all(uploads_dir()).await {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let owner = auth::current_user().map(|u| u.username);
+    let info = UploadInfo { length, path, volume, filename, owner };
+    let result = async {
+        fs::write(data_path(&id), b"").await?;
+        fs::write(info_path(&id), serde_json::to_vec(&info)?).await
+    }
+    .await;
+    if let Err(e) = result {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+
+    // An empty file is complete as soon as it is created
+    if length == 0
+        && let Err((status, e)) = finalize(&state, &id, &info).await
+    {
+        return tus_error(status, e);
+    }
+
+    let mut response = (
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/uploads/{}", id))],
+    )
+        .into_response();
+    insert_expires(&mut response, SystemTime::now());
+    with_tus_headers(response)
+}
+
+async fn upload_status(UrlPath(id): UrlPath<String>, headers: HeaderMap) -> Response {
+    if !supports_version(&headers) {
+        return version_mismatch();
+    }
+    if !fs_ops::is_stored_id(&id) {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    }
+
+    let (Some(info), Ok(metadata)) = (load_info(&id).await, fs::metadata(data_path(&id)).await) else {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    };
+
+    let mut response = (
+        StatusCode::OK,
+        [
+            (UPLOAD_OFFSET, metadata.len().to_string()),
+            (UPLOAD_LENGTH, info.length.to_string()),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+    )
+        .into_response();
+    if let Ok(modified) = metadata.modified() {
+        insert_expires(&mut response, modified);
+    }
+    with_tus_headers(response)
+}
+
+async fn append_chunk(
+    State(state): State<TusState>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if !supports_version(&headers) {
+        return version_mismatch();
+    }
+    if !fs_ops::is_stored_id(&id) {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    }
+
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if content_type != Some("application/offset+octet-stream") {
+        return tus_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected application/offset+octet-stream");
+    }
+    let Some(offset) = header_u64(&headers, &UPLOAD_OFFSET) else {
+        return tus_error(StatusCode::BAD_REQUEST, "Missing or invalid Upload-Offset");
+    };
+
+    let Some(_guard) = state.acquire(&id) else {
+        return tus_error(StatusCode::CONFLICT, "Upload is already being written to");
+    };
+    let Some(info) = load_info(&id).await else {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    };
+
+    let mut file = match fs::OpenOptions::new().append(true).open(data_path(&id)).await {
+        Ok(f) => f,
+        Err(_) => return tus_error(StatusCode::NOT_FOUND, "Upload not found"),
+    };
+    let mut written = match file.metadata().await {
+        Ok(m) => m.len(),
+        Err(e) => return tus_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    if offset != written {
+        let response = (StatusCode::CONFLICT, [(UPLOAD_OFFSET, written.to_string())], "Upload-Offset mismatch");
+        return with_tus_headers(response.into_response());
+    }
+
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        // A dropped connection keeps whatever arrived, so the client can resume from there
+        let Ok(chunk) = chunk else { break };
+
+        if written + chunk.len() as u64 > info.length {
+            let _ = file.flush().await;
+            return tus_error(StatusCode::PAYLOAD_TOO_LARGE, "Chunk exceeds Upload-Length");
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            return tus_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+        written += chunk.len() as u64;
+    }
+
+    if let Err(e) = file.sync_data().await {
+        return tus_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    drop(file);
+
+    if written == info.length
+        && let Err((status, e)) = finalize(&state, &id, &info).await
+    {
+        return tus_error(status, e);
+    }
+
+    let mut response = (StatusCode::NO_CONTENT, [(UPLOAD_OFFSET, written.to_string())]).into_response();
+    insert_expires(&mut response, SystemTime::now());
+    with_tus_headers(response)
+}
+
+async fn terminate_upload(
+    State(state): State<TusState>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !supports_version(&headers) {
+        return version_mismatch();
+    }
+    if !fs_ops::is_stored_id(&id) {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    }
+    let Some(_guard) = state.acquire(&id) else {
+        return tus_error(StatusCode::CONFLICT, "Upload is already being written to");
+    };
+    if load_info(&id).await.is_none() {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    }
+
+    if fs::remove_file(info_path(&id)).await.is_err() {
+        return tus_error(StatusCode::NOT_FOUND, "Upload not found");
+    }
+    let _ = fs::remove_file(data_path(&id)).await;
+
+    with_tus_headers(StatusCode::NO_CONTENT.into_response())
+}
+
+// Moves a completed upload into its target folder
+async fn finalize(state: &TusState, id: &str, info: &UploadInfo) -> Result<(), (StatusCode, String)> {
+    if !auth::owns_job(info.owner.as_deref()) {
+        return Err((StatusCode::NOT_FOUND, "Upload not found".to_string()));
+    }
+    let target_dir = resolve_writable(info.volume.as_deref(), Some(info.path.clone())).map_err(|e| (e.status(), e.to_string()))?;
+    if !target_dir.is_dir() {
+        return Err((StatusCode::CONFLICT, "Target folder no longer exists".to_string()));
+    }
+
+    // The uploads folder may sit on a different filesystem than the target
+    let (from, to) = (data_path(id), target_dir.join(&info.filename));
+    // Rules may have changed while the upload was running
+    acl::check(&to, Permission::Write)?;
+    if fs::symlink_metadata(&to).await.is_ok_and(|m| m.is_dir()) {
+        return Err(folder_in_the_way());
+    }
+    let target = to.clone();
+    versions::keep(&target).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    tokio::task::spawn_blocking(move || fs_ops::move_file(&from, &to))
+        .await
+        .unwrap()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::IsADirectory => folder_in_the_way(),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+    versions::record_upload(&target).await;
+    quota::invalidate(&target);
+    disk_usage::invalidate(&target);
+    state.index.refresh(target);
+    let _ = fs::remove_file(info_path(id)).await;
+    Ok(())
+}
+
+// Uploads only ever replace files
+fn folder_in_the_way() -> (StatusCode, String) {
+    (StatusCode::CONFLICT, "A folder with that name already exists".to_string())
+}
+
+async fn sweep_expired() {
+    let Ok(mut read_dir) = fs::read_dir(uploads_dir()).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "bin") {
+            continue;
+        }
+        let expired = entry
+            .metadata()
+            .await
+            .and_then(|m| m.modified())
+            .map(|modified| modified + UPLOAD_TTL < SystemTime::now())
+            .unwrap_or(false);
+        if expired {
+            let _ = fs::remove_file(path.with_extension("json")).await;
+            let _ = fs::remove_file(&path).await;
+        }
+    }
+}
+
+// Uploads of other users read as missing
+async fn load_info(id: &str) -> Option<UploadInfo> {
+    let data = fs::read(info_path(id)).await.ok()?;
+    let info: UploadInfo = serde_json::from_slice(&data).ok()?;
+    auth::owns_job(info.owner.as_deref()).then_some(info)
+}
+
+fn uploads_dir() -> &'static Path {
+    &config::get().uploads_dir
+}
+
+fn data_path(id: &str) -> PathBuf {
+    uploads_dir().join(format!("{}.bin", id))
+}
+
+fn info_path(id: &str) -> PathBuf {
+    uploads_dir().join(format!("{}.json", id))
+}
+
+// Ids are only ever generated by us, so anything else can't name an upload
+fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+// `Upload-Metadata: key base64value,key2 base64value2`
+fn parse_metadata(value: &HeaderValue) -> Result<Vec<(String, String)>, String> {
+    let value = value.to_str().map_err(|_| "Invalid Upload-Metadata".to_string())?;
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, encoded) = pair.split_once(' ').unwrap_or((pair, ""));
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .map_err(|_| format!("Invalid base64 for metadata key {}", key))?;
+            let decoded = String::from_utf8(decoded).map_err(|_| format!("Metadata key {} is not UTF-8", key))?;
+            Ok((key.to_string(), decoded))
+        })
+        .collect()
+}
+
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+fn supports_version(headers: &HeaderMap) -> bool {
+    headers.get(&TUS_RESUMABLE).is_some_and(|v| v == TUS_VERSION)
+}
+
+fn version_mismatch() -> Response {
+    let response = (
+        StatusCode::PRECONDITION_FAILED,
+        [(TUS_VERSION_HEADER, TUS_VERSION)],
+        "Unsupported Tus-Resumable version",
+    );
+    with_tus_headers(response.into_response())
+}
+
+fn insert_expires(response: &mut Response, last_activity: SystemTime) {
+    if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(last_activity + UPLOAD_TTL)) {
+        response.headers_mut().insert(UPLOAD_EXPIRES, value);
+    }
+}
+
+fn with_tus_headers(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn tus_error(status: StatusCode, message: impl Into<String>) -> Response {
+    with_tus_headers((status, message.into()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{run_as, test_user};
+
+    fn state() -> TusState {
+        TusState { busy: Default::default(), index: ContentIndex::default() }
+    }
+
+    // A fresh folder on the test volume, as passed to `path`
+    fn folder(name: &str) -> (String, PathBuf) {
+        let dir = config::for_tests().volumes[0].path.join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        (name.to_string(), dir)
+    }
+
+    fn tus_headers(pairs: &[(HeaderName, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    async fn create(path: &str, filename: &str, length: u64) -> Response {
+        let encode = |v: &str| base64::engine::general_purpose::STANDARD.encode(v);
+        let metadata = format!("filename {},path {}", encode(filename), encode(path));
+        let headers = tus_headers(&[(UPLOAD_LENGTH, length.to_string()), (UPLOAD_METADATA, metadata)]);
+        create_upload(State(state()), headers).await
+    }
+
+    fn upload_id(response: &Response) -> String {
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        location.rsplit('/').next().unwrap().to_string()
+    }
+
+    async fn patch(id: &str, offset: u64, data: &'static str) -> Response {
+        let mut headers = tus_headers(&[(UPLOAD_OFFSET, offset.to_string())]);
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/offset+octet-stream"));
+        append_chunk(State(state()), UrlPath(id.to_string()), headers, Body::from(data)).await
+    }
+
+    async fn head(id: &str) -> Response {
+        upload_status(UrlPath(id.to_string()), tus_headers(&[])).await
+    }
+
+    fn offset(response: &Response) -> &str {
+        response.headers()[UPLOAD_OFFSET].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunks_have_to_continue_at_the_current_offset() {
+        let (path, dir) = folder("tus_offsets");
+        let created = create(&path, "file.txt", 6).await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let id = upload_id(&created);
+
+        let first = patch(&id, 0, "abc").await;
+        assert_eq!(first.status(), StatusCode::NO_CONTENT);
+        assert_eq!(offset(&first), "3");
+
+        let repeated = patch(&id, 0, "abc").await;
+        assert_eq!(repeated.status(), StatusCode::CONFLICT);
+        assert_eq!(offset(&repeated), "3");
+        assert_eq!(offset(&head(&id).await), "3");
+        assert!(!dir.join("file.txt").exists());
+
+        assert_eq!(patch(&id, 3, "def").await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read_to_string(dir.join("file.txt")).unwrap(), "abcdef");
+        assert_eq!(head(&id).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn chunks_past_the_length_are_refused() {
+        let (path, dir) = folder("tus_too_long");
+        let id = upload_id(&create(&path, "file.txt", 2).await);
+        assert_eq!(patch(&id, 0, "abc").await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.join("file.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_uploads_are_complete_when_created() {
+        let (path, dir) = folder("tus_empty");
+        assert_eq!(create(&path, "empty.txt", 0).await.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read(dir.join("empty.txt")).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn uploads_never_replace_a_folder() {
+        let (path, dir) = folder("tus_onto_folder");
+        std::fs::create_dir(dir.join("taken")).unwrap();
+        std::fs::write(dir.join("taken/keep.txt"), "keep").unwrap();
+        assert_eq!(create(&path, "taken", 3).await.status(), StatusCode::CONFLICT);
+
+        // The folder may also turn up while the upload is running
+        let id = upload_id(&create(&path, "later", 3).await);
+        std::fs::create_dir(dir.join("later")).unwrap();
+        std::fs::write(dir.join("later/keep.txt"), "keep").unwrap();
+        assert_eq!(patch(&id, 0, "abc").await.status(), StatusCode::CONFLICT);
+        assert_eq!(std::fs::read_to_string(dir.join("taken/keep.txt")).unwrap(), "keep");
+        assert_eq!(std::fs::read_to_string(dir.join("later/keep.txt")).unwrap(), "keep");
+    }
+
+    #[tokio::test]
+    async fn uploads_belong_to_whoever_created_them() {
+        let (path, dir) = folder("tus_owner");
+        let created = run_as(Some(test_user("tus_alice")), create(&path, "file.txt", 3)).await;
+        let id = upload_id(&created);
+        assert!(info_path(&id).exists());
+
+        let mallory = || Some(test_user("tus_mallory"));
+        assert_eq!(run_as(mallory(), head(&id)).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(run_as(mallory(), patch(&id, 0, "abc")).await.status(), StatusCode::NOT_FOUND);
+        let delete = terminate_upload(State(state()), UrlPath(id.clone()), tus_headers(&[]));
+        assert_eq!(run_as(mallory(), delete).await.status(), StatusCode::NOT_FOUND);
+        assert!(!dir.join("file.txt").exists());
+
+        let alice = || Some(test_user("tus_alice"));
+        assert_eq!(run_as(alice(), head(&id)).await.status(), StatusCode::OK);
+        assert_eq!(run_as(alice(), patch(&id, 0, "abc")).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read_to_string(dir.join("file.txt")).unwrap(), "abc");
+    }
+}
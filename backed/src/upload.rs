@@ -0,0 +1,137 @@
+use axum::{
+    extract::{multipart::Field, DefaultBodyLimit},
+    http::StatusCode,
+};
+use std::path::{Path, PathBuf};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{config, fs_ops, quota, versions};
+
+// For routes that take uploads. They are streamed to disk, so the general
+// body limit doesn't apply, only `max_upload_bytes`.
+pub fn body_limit() -> DefaultBodyLimit {
+    match config::get().max_upload_bytes {
+        Some(max) => DefaultBodyLimit::max(max as usize),
+        None => DefaultBodyLimit::disable(),
+    }
+}
+
+// A partially written upload. It sits next to its destination so the final
+// rename is atomic, and is removed again unless `persist` succeeds.
+pub struct TempFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempFile {
+    fn beside(target: &Path) -> Self {
+        Self { path: fs_ops::staging_path(target), keep: false }
+    }
+
+    pub async fn persist(mut self, target: &Path) -> std::io::Result<()> {
+        fs::rename(&self.path, target).await?;
+        self.keep = true;
+        Ok(())
+    }
+
+    // Like `persist`, but fails with `AlreadyExists` rather than replace
+    // anything at `target`, even something created a moment ago by another
+    // request. Where the filesystem has no hard links the data is copied
+    // into a newly created file instead.
+    pub async fn persist_new(&self, target: &Path) -> std::io::Result<()> {
+        match fs::hard_link(&self.path, target).await {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+                let mut output = fs::OpenOptions::new().write(true).create_new(true).open(target).await?;
+                let copied = async {
+                    tokio::io::copy(&mut fs::File::open(&self.path).await?, &mut output).await?;
+                    output.sync_all().await
+                };
+                if let Err(e) = copied.await {
+                    let _ = fs::remove_file(target).await;
+                    return Err(e);
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Only the last component of a client supplied name is used, so a field
+// can't write outside the folder it was uploaded to
+pub fn field_file_name(field: &Field<'_>) -> Option<String> {
+    let name = Path::new(field.file_name()?).file_name()?.to_string_lossy().to_string();
+    if name.is_empty() { None } else { Some(name) }
+}
+
+// Streams a multipart field to `target` chunk by chunk. Readers never see a
+// half-written file: the data only appears under its real name once complete.
+// Writing more than `limit` bytes fails with 507 and keeps nothing. A file
+// that is replaced is kept as a version first.
+pub async fn save_field(field: Field<'_>, target: &Path, limit: Option<u64>) -> Result<u64, (StatusCode, String)> {
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let (temp, written) = receive_field(field, target, limit).await?;
+    versions::keep(target).await.map_err(internal)?;
+    temp.persist(target).await.map_err(internal)?;
+    versions::record_upload(target).await;
+    Ok(written)
+}
+
+// The first half of `save_field`: the data is complete on disk, but not yet
+// under `target`, for callers that settle on the final name afterwards
+pub async fn receive_field(
+    mut field: Field<'_>,
+    target: &Path,
+    limit: Option<u64>,
+) -> Result<(TempFile, u64), (StatusCode, String)> {
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    let temp = TempFile::beside(target);
+    let mut file = fs::File::create(&temp.path).await.map_err(internal)?;
+    let mut written = 0u64;
+
+    // An aborted request surfaces here as an error, and dropping `temp` cleans up
+    while let Some(chunk) = field.chunk().await.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))? {
+        written += chunk.len() as u64;
+        if limit.is_some_and(|limit| written > limit) {
+            return Err(quota::exceeded());
+        }
+        file.write_all(&chunk).await.map_err(internal)?;
+    }
+
+    file.sync_all().await.map_err(internal)?;
+    Ok((temp, written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn new_files_never_replace_anything() {
+        let dir = std::env::temp_dir().join(format!("disk_manager_upload_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("taken"), "old").unwrap();
+
+        let temp = TempFile::beside(&dir.join("taken"));
+        std::fs::write(&temp.path, "new").unwrap();
+        let error = temp.persist_new(&dir.join("taken")).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(dir.join("taken")).unwrap(), "old");
+
+        temp.persist_new(&dir.join("free")).await.unwrap();
+        let staged = temp.path.clone();
+        drop(temp);
+        assert_eq!(std::fs::read_to_string(dir.join("free")).unwrap(), "new");
+        assert!(!staged.exists());
+    }
+}
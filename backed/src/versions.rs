@@ -0,0 +1,352 @@
+// Earlier content of files replaced by uploads. Each file gets a folder in
+// `.versions` at the root of its volume, named after a hash of its path, that
+// holds one folder per version next to a JSON record of it, plus a record of
+// who uploaded the current content. Old versions are dropped once there are
+// more than `keep_last` of them or they are older than `keep_days`, and are
+// charged to the owner of the home the file is in until then.
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json as AxumJson},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::fs;
+
+use crate::{
+    acl::{self, Permission},
+    auth::{self, unix_now},
+    config::{self, Volume},
+    content_index::ContentIndex,
+    disk_usage, fs_ops, quota,
+    sandbox::{self, resolve_path, resolve_writable},
+};
+
+pub const VERSIONS_DIR: &str = ".versions";
+const CURRENT_FILE: &str = "current.json";
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Version {
+    id: String,
+    // Relative to the volume root; shown relative to the caller's root
+    path: String,
+    size: u64,
+    // When this content was written, unix seconds
+    modified: Option<u64>,
+    // When an upload replaced it, unix seconds
+    replaced_at: u64,
+    // Who uploaded this content, if it came in through the server
+    uploader: Option<String>,
+}
+
+// Who uploaded what is in the file right now
+#[derive(Default, Serialize, Deserialize)]
+struct Current {
+    // Relative to the volume root
+    #[serde(default)]
+    path: String,
+    uploader: Option<String>,
+}
+
+pub fn router(index: ContentIndex) -> Router {
+    Router::new()
+        .route("/", get(list_versions))
+        .route("/:id/download", get(download_version))
+        .route("/:id/restore", post(restore_version))
+        .with_state(index)
+}
+
+fn history_dir(volume: &Volume, path: &Path) -> PathBuf {
+    let relative = sandbox::volume_relative(path);
+    let key = blake2b_simd::Params::new().hash_length(16).hash(relative.as_bytes());
+    volume.path.join(VERSIONS_DIR).join(key.to_hex().as_str())
+}
+
+fn record_path(history: &Path, id: &str) -> PathBuf {
+    history.join(format!("{}.json", id))
+}
+
+// Who is charged for a version: the user whose home the file is in
+fn owner(history: &Path, version: &Version) -> Option<String> {
+    let volume = sandbox::volume_of(history)?;
+    quota::owner_of(&volume.path.join(&version.path))
+}
+
+// Bytes of all versions charged to `username`
+pub fn owned_bytes(username: &str) -> u64 {
+    let histories = sandbox::volumes()
+        .into_iter()
+        .filter_map(|volume| std::fs::read_dir(volume.path.join(VERSIONS_DIR)).ok())
+        .flatten()
+        .filter_map(Result::ok);
+    let mut total = 0;
+    for history in histories {
+        let Ok(entries) = std::fs::read_dir(history.path()) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            if entry.path().extension().is_none_or(|e| e != "json") || entry.file_name() == CURRENT_FILE {
+                continue;
+            }
+            if let Ok(data) = std::fs::read(entry.path())
+                && let Ok(version) = serde_json::from_slice::<Version>(&data)
+                && owner(&history.path(), &version).as_deref() == Some(username)
+            {
+                total += version.size;
+            }
+        }
+    }
+    total
+}
+
+// Saves the current content of `path` as a version, if there is any, before
+// something replaces it. The content is hard linked where possible, so the
+// file stays in place until the replacement is renamed over it.
+pub async fn keep(path: &Path) -> io::Result<()> {
+    if !config::get().features.versions {
+        return Ok(());
+    }
+    let Ok(metadata) = fs::metadata(path).await else {
+        return Ok(());
+    };
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    let volume = sandbox::volume_of(path).ok_or_else(|| io::Error::other("Path is not on a volume"))?;
+    let history = history_dir(volume, path);
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+    let (source, destination) = (path.to_path_buf(), history.join(&id).join(&name));
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(destination.parent().unwrap())?;
+        if std::fs::hard_link(&source, &destination).is_err() {
+            std::fs::copy(&source, &destination)?;
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await
+    .unwrap()?;
+
+    let version = Version {
+        id: id.clone(),
+        path: sandbox::volume_relative(path),
+        size: metadata.len(),
+        modified: metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+        replaced_at: unix_now(),
+        uploader: read_current(&history).await.uploader,
+    };
+    fs::write(record_path(&history, &id), serde_json::to_vec(&version)?).await?;
+    quota::record(path, version.size as i64);
+    prune(&history).await;
+    Ok(())
+}
+
+// Notes who put the current content of `path` there
+pub async fn record_upload(path: &Path) {
+    if !config::get().features.versions {
+        return;
+    }
+    let Some(volume) = sandbox::volume_of(path) else {
+        return;
+    };
+    let current = Current { path: sandbox::volume_relative(path), uploader: auth::current_user().map(|u| u.username) };
+    if let Err(e) = write_current(&history_dir(volume, path), &current).await {
+        tracing::warn!("could not record uploader of {}: {}", path.display(), e);
+    }
+}
+
+async fn read_current(history: &Path) -> Current {
+    let data = fs::read(history.join(CURRENT_FILE)).await.unwrap_or_default();
+    serde_json::from_slice(&data).unwrap_or_default()
+}
+
+async fn write_current(history: &Path, current: &Current) -> io::Result<()> {
+    fs::create_dir_all(history).await?;
+    fs::write(history.join(CURRENT_FILE), serde_json::to_vec(current)?).await
+}
+
+// Versions of one file, newest first
+async fn load_versions(history: &Path) -> Vec<Version> {
+    let mut versions = Vec::new();
+    let Ok(mut read_dir) = fs::read_dir(history).await else {
+        return versions;
+    };
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "json") || entry.file_name() == CURRENT_FILE {
+            continue;
+        }
+        if let Ok(data) = fs::read(&path).await
+            && let Ok(version) = serde_json::from_slice::<Version>(&data)
+        {
+            versions.push(version);
+        }
+    }
+    versions.sort_by_key(|v| std::cmp::Reverse(v.replaced_at));
+    versions
+}
+
+async fn remove_version(history: &Path, version: &Version) -> io::Result<()> {
+    let holder = history.join(&version.id);
+    if fs::metadata(&holder).await.is_ok() {
+        fs::remove_dir_all(&holder).await?;
+    }
+    fs::remove_file(record_path(history, &version.id)).await?;
+    if let Some(owner) = owner(history, version) {
+        quota::record_for(&owner, -(version.size as i64));
+    }
+    Ok(())
+}
+
+// Applies the retention settings to one file's versions
+async fn prune(history: &Path) {
+    let settings = &config::get().versions;
+    let cutoff = settings.keep_days.map(|days| unix_now().saturating_sub(days * 24 * 60 * 60));
+    for (i, version) in load_versions(history).await.iter().enumerate() {
+        let surplus = settings.keep_last.is_some_and(|keep| i >= keep);
+        let expired = cutoff.is_some_and(|cutoff| version.replaced_at < cutoff);
+        if (surplus || expired)
+            && let Err(e) = remove_version(history, version).await
+        {
+            tracing::warn!("could not remove version {}: {}", version.id, e);
+        }
+    }
+}
+
+// Drops expired versions now and then for as long as the server runs, and
+// forgets files that are gone once none of their versions are left
+pub fn spawn_retention_purge() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            for volume in sandbox::volumes().into_iter().filter(|v| !v.read_only) {
+                let Ok(mut read_dir) = fs::read_dir(volume.path.join(VERSIONS_DIR)).await else {
+                    continue;
+                };
+                while let Ok(Some(entry)) = read_dir.next_entry().await {
+                    let history = entry.path();
+                    prune(&history).await;
+                    if !load_versions(&history).await.is_empty() {
+                        continue;
+                    }
+                    let current = read_current(&history).await;
+                    if current.path.is_empty() || fs::metadata(volume.path.join(&current.path)).await.is_err() {
+                        let _ = fs::remove_dir_all(&history).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    path: String,
+    volume: Option<String>,
+}
+
+// The file a request is about, and the folder holding its versions
+fn locate(query: &VersionQuery, writable: bool) -> Result<(PathBuf, PathBuf), (StatusCode, String)> {
+    let resolve = if writable { resolve_writable } else { resolve_path };
+    let path = resolve(query.volume.as_deref(), Some(query.path.clone())).map_err(|e| (e.status(), e.to_string()))?;
+    acl::check(&path, if writable { Permission::Write } else { Permission::Read })?;
+    let volume = sandbox::volume_of(&path).ok_or((StatusCode::NOT_FOUND, "No such volume".to_string()))?;
+    let history = history_dir(volume, &path);
+    Ok((path, history))
+}
+
+async fn find_version(history: &Path, id: &str) -> Option<Version> {
+    if !fs_ops::is_stored_id(id) {
+        return None;
+    }
+    let data = fs::read(record_path(history, id)).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+async fn list_versions(Query(query): Query<VersionQuery>) -> impl IntoResponse {
+    let (path, history) = match locate(&query, false) {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let versions: Vec<Version> = load_versions(&history)
+        .await
+        .into_iter()
+        .map(|mut version| {
+            version.path = sandbox::relative_path(&path);
+            version
+        })
+        .collect();
+    AxumJson(versions).into_response()
+}
+
+async fn download_version(
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<VersionQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let (path, history) = match locate(&query, false) {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    if find_version(&history, &id).await.is_none() {
+        return (StatusCode::NOT_FOUND, "No such version").into_response();
+    }
+    crate::send_path(&history.join(&id).join(path.file_name().unwrap_or_default()), &headers).await
+}
+
+// The current content becomes a version itself, so a restore can be undone
+async fn restore_version(
+    State(index): State<ContentIndex>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<VersionQuery>,
+) -> impl IntoResponse {
+    let (target, history) = match locate(&query, true) {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let Some(version) = find_version(&history, &id).await else {
+        return (StatusCode::NOT_FOUND, "No such version").into_response();
+    };
+    if fs::metadata(&target).await.is_ok_and(|m| m.is_dir()) {
+        return (StatusCode::CONFLICT, "A folder now exists at this path").into_response();
+    }
+    // The current content is kept as a version, so nothing is freed
+    let replaced = fs::metadata(&target).await.map(|m| m.len()).unwrap_or(0);
+    if let Err(e) = quota::check(version.size).await {
+        return e.into_response();
+    }
+
+    // Copied out first, as saving the current content may prune this version
+    let source = history.join(&id).join(target.file_name().unwrap_or_default());
+    let staging = fs_ops::staging_path(&target);
+    let copy = (source, staging.clone());
+    if let Err(e) = tokio::task::spawn_blocking(move || std::fs::copy(&copy.0, &copy.1)).await.unwrap() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    let result = match keep(&target).await {
+        Ok(()) => fs::rename(&staging, &target).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&staging).await;
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    let current = Current { path: version.path, uploader: version.uploader };
+    if let Err(e) = write_current(&history, &current).await {
+        tracing::warn!("could not record uploader of {}: {}", target.display(), e);
+    }
+    quota::record(&target, version.size as i64 - replaced as i64);
+    disk_usage::invalidate(&target);
+    index.refresh(target.clone());
+    (StatusCode::OK, sandbox::relative_path(&target)).into_response()
+}
@@ -0,0 +1,109 @@
+use axum::body::{Body, Bytes};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use walkdir::WalkDir;
+
+use crate::{
+    acl::{self, Permission},
+    auth, sandbox,
+};
+
+// Size of the chunks handed to the response body, and how many of them may
+// be queued before the zip writer blocks on a slow client
+const CHUNK_SIZE: usize = 64 * 1024;
+const QUEUED_CHUNKS: usize = 8;
+
+// Zips `dir` on a blocking thread and streams the archive as it is written.
+// Memory use is bounded by the chunk queue no matter how large the folder is.
+pub fn zip_directory(dir: PathBuf) -> Body {
+    let (tx, rx) = mpsc::channel(QUEUED_CHUNKS);
+    let user = auth::current_user();
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter::new(tx.clone());
+        let result = auth::with_user(user, || write_zip(&dir, &mut writer)).and_then(|_| writer.flush());
+
+        // Once headers are out the only way to report a failure is to break
+        // the body, so the client doesn't mistake a truncated archive for a
+        // complete one
+        if let Err(e) = result
+            && e.kind() != io::ErrorKind::BrokenPipe
+        {
+            tracing::warn!("zip of {} failed: {}", dir.display(), e);
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    Body::from_stream(ReceiverStream::new(rx))
+}
+
+fn write_zip(dir: &Path, writer: &mut ChannelWriter) -> io::Result<()> {
+    let mut zip = zip::ZipWriter::new_stream(writer);
+    let parent_dir = dir.parent().unwrap_or(dir);
+
+    for entry in WalkDir::new(dir).into_iter().filter_entry(|e| !sandbox::is_reserved(e.path())) {
+        let entry = entry?;
+        let path = entry.path();
+
+        // Links to files are only followed while they stay inside the volume,
+        // and files the user can't read are left out of the archive
+        let file_type = entry.file_type();
+        let is_file =
+            file_type.is_file() || (file_type.is_symlink() && path.is_file() && sandbox::link_stays_inside(path));
+        if is_file && acl::allows(path, Permission::Read) {
+            let name = path.strip_prefix(parent_dir).unwrap_or(path);
+            let mut f = std::fs::File::open(path)?;
+            let size = f.metadata()?.len();
+
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored)
+                .large_file(size >= u32::MAX as u64);
+
+            zip.start_file_from_path(name, options)?;
+            io::copy(&mut f, &mut zip)?;
+        }
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn new(tx: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self { tx, buf: Vec::with_capacity(CHUNK_SIZE) }
+    }
+
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        // The receiver is dropped when the client goes away
+        self.tx
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buffered()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()
+    }
+}